@@ -0,0 +1,76 @@
+/*
+ *  Fuzz target of the CSV input and the payment engine. Arbitrary bytes are read as
+ *  the command line tool reads a CSV input, the transactions are applied and the balances
+ *  are written. Nothing shall panic or overflow, and the invariants of the accounts shall
+ *  hold after every transaction.
+ *
+ *  Run it with:   cargo +nightly fuzz run csv_engine
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+#![no_main]
+
+use csv::{StringRecord, Trim};
+use libfuzzer_sys::fuzz_target;
+
+use csv_payment::{read_transaction, write_csv_accounts, Amount, DecodingReader, KindAliases, LockedPolicy, PaymentEngine};
+
+
+fuzz_target!(|data: &[u8]| {
+    for policy in [LockedPolicy::Reject, LockedPolicy::Queue].iter() {
+        process(data, *policy);
+    }
+});
+
+/**
+ * Read and apply all the transactions of the input, as the command line tool does
+ */
+fn process(in_data: &[u8], in_locked_policy: LockedPolicy) {
+    let mut engine = PaymentEngine::with_locked_policy(in_locked_policy);
+    let aliases = KindAliases::new();
+
+    let mut csv_reader = csv::ReaderBuilder::new()
+                                     .trim(Trim::All)
+                                     .flexible(true)
+                                     .from_reader( DecodingReader::new(in_data, None) );
+
+    let headers = match csv_reader.byte_headers().map(|h| StringRecord::from_byte_record(h.clone())) {
+        Ok(Ok(h)) => h,
+        _         => return,
+    };
+    let type_column = headers.iter().position(|h| h == "type");
+
+    for current_record in csv_reader.byte_records() {
+        // The tool stops reading the file at the first record that can not be read
+        let current_record = match current_record {
+            Ok(r)  => r,
+            Err(_) => break,
+        };
+
+        if let Ok(tx) = read_transaction(&current_record, &headers, type_column, &aliases) {
+            let client_id = tx.client_id;
+            let _ = engine.apply(&tx);
+            check_account(&engine, client_id);
+        }
+    }
+
+    for account in engine.accounts() {
+        check_account(&engine, account.client_id);
+    }
+
+    // The balances are written as the tool does. It shall never fail
+    write_csv_accounts(Vec::new(), &engine.snapshot()).expect("Writing accounts");
+}
+
+/**
+ * Invariants of an account: available + held == total, and held is never negative
+ */
+fn check_account(in_engine: &PaymentEngine, in_client_id: u16) {
+    if let Some(account) = in_engine.account(in_client_id) {
+        assert_eq!(account.available.checked_add(account.held), Some(account.total), "available + held != total: {:?}", account);
+        assert!(account.held >= Amount::ZERO, "held is negative: {:?}", account);
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ *  Fixed-point decimal money type used for every amount and balance
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+
+/// Number of fractional digits kept by an `Amount`
+pub const DECIMALS: usize = 4;
+
+/// Value of one unit expressed in the internal representation (10^DECIMALS)
+const SCALE: i64 = 10_000;
+
+
+/**
+ * Exact decimal amount with four fractional digits.
+ *
+ * It is stored as a signed count of 1/10000 units, so additions and subtractions
+ * never drift. All arithmetic is checked and reports overflow instead of wrapping.
+ *
+ * Parsing rule: inputs with more than four fractional digits are rejected, unless
+ * the extra digits are all zeros (i.e. "1.50000" is accepted, "1.00005" is not).
+ * Values are never rounded.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /**
+     * Amount from its internal representation, a count of 1/10000 units
+     */
+    pub const fn from_raw(in_raw: i64) -> Amount {
+        Amount(in_raw)
+    }
+
+    /**
+     * Internal representation, a count of 1/10000 units
+     */
+    pub const fn raw(self) -> i64 {
+        self.0
+    }
+
+    pub fn checked_add(self, in_other: Amount) -> Option<Amount> {
+        self.0.checked_add(in_other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, in_other: Amount) -> Option<Amount> {
+        self.0.checked_sub(in_other.0).map(Amount)
+    }
+}
+
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmountError {
+    /// The field is empty
+    Empty,
+    /// The text is not a decimal number
+    Invalid(String),
+    /// The number has more than DECIMALS significant fractional digits
+    TooPrecise(String),
+    /// The number does not fit in the internal representation
+    Overflow(String),
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmountError::Empty         => write!(f, "Empty amount"),
+            AmountError::Invalid(s)    => write!(f, "Invalid amount: {}", s),
+            AmountError::TooPrecise(s) => write!(f, "Amount has more than {} decimal digits: {}", DECIMALS, s),
+            AmountError::Overflow(s)   => write!(f, "Amount out of range: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(in_text: &str) -> Result<Self, Self::Err> {
+        let text = in_text.trim();
+        if text.is_empty() {
+            return Err(AmountError::Empty);
+        }
+
+        let invalid = || AmountError::Invalid(text.to_string());
+        let overflow = || AmountError::Overflow(text.to_string());
+
+        // Optional sign
+        let (negative, digits) = match text.as_bytes()[0] {
+            b'-' => (true, &text[1..]),
+            b'+' => (false, &text[1..]),
+            _    => (false, text),
+        };
+
+        let (int_part, frac_part) = match digits.find('.') {
+            Some(pos) => (&digits[..pos], &digits[pos + 1..]),
+            None      => (digits, ""),
+        };
+
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Err(invalid());
+        }
+        if !int_part.bytes().all(|b| b.is_ascii_digit()) || !frac_part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        // Extra fractional digits are only allowed if they are zeros
+        let (frac_kept, frac_extra) = if frac_part.len() > DECIMALS {
+            frac_part.split_at(DECIMALS)
+        } else {
+            (frac_part, "")
+        };
+        if frac_extra.bytes().any(|b| b != b'0') {
+            return Err(AmountError::TooPrecise(text.to_string()));
+        }
+
+        let mut value: i64 = 0;
+        for b in int_part.bytes() {
+            value = value.checked_mul(10)
+                         .and_then(|v| v.checked_add(i64::from(b - b'0')))
+                         .ok_or_else(overflow)?;
+        }
+        value = value.checked_mul(SCALE).ok_or_else(overflow)?;
+
+        let mut fraction: i64 = 0;
+        for b in frac_kept.bytes() {
+            fraction = fraction * 10 + i64::from(b - b'0');
+        }
+        for _ in frac_kept.len()..DECIMALS {
+            fraction *= 10;
+        }
+        value = value.checked_add(fraction).ok_or_else(overflow)?;
+
+        if negative {
+            value = -value;
+        }
+
+        Ok(Amount(value))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+
+        write!(f, "{}{}.{:0width$}", sign, abs / scale, abs % scale, width = DECIMALS)
+    }
+}
+
+// ---------------------------------------------------------------------
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, in_serializer: S) -> Result<S::Ok, S::Error> {
+        in_serializer.collect_str(self)
+    }
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal amount with at most {} fractional digits", DECIMALS)
+    }
+
+    fn visit_str<E: de::Error>(self, in_value: &str) -> Result<Amount, E> {
+        in_value.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, in_value: i64) -> Result<Amount, E> {
+        in_value.checked_mul(SCALE)
+                .map(Amount)
+                .ok_or_else(|| E::custom(AmountError::Overflow(in_value.to_string())))
+    }
+
+    fn visit_u64<E: de::Error>(self, in_value: u64) -> Result<Amount, E> {
+        i64::try_from(in_value)
+            .ok()
+            .and_then(|v| v.checked_mul(SCALE))
+            .map(Amount)
+            .ok_or_else(|| E::custom(AmountError::Overflow(in_value.to_string())))
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(in_deserializer: D) -> Result<Self, D::Error> {
+        in_deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+// ---------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid() {
+        let case_list = [
+            ("1",          10_000),
+            ("1.5",        15_000),
+            ("0.0001",     1),
+            (".5",         5_000),
+            ("5.",         50_000),
+            ("+2.25",      22_500),
+            ("-3.1",       -31_000),
+            (" 7.25 ",     72_500),
+            ("1.50000",    15_000),
+            ("0",          0),
+            ("-0.0000",    0),
+            ("922337203685477.5807", i64::MAX),
+        ];
+
+        for (text, raw) in case_list.iter() {
+            assert_eq!(text.parse::<Amount>(), Ok(Amount(*raw)), "Parsing: {:?}", text);
+        }
+    }
+
+    #[test]
+    fn parse_rejected() {
+        let case_list = [
+            ("",                       AmountError::Empty),
+            ("   ",                    AmountError::Empty),
+            ("abc",                    AmountError::Invalid("abc".to_string())),
+            (".",                      AmountError::Invalid(".".to_string())),
+            ("-",                      AmountError::Invalid("-".to_string())),
+            ("1.2.3",                  AmountError::Invalid("1.2.3".to_string())),
+            ("1e5",                    AmountError::Invalid("1e5".to_string())),
+            ("1,5",                    AmountError::Invalid("1,5".to_string())),
+            ("--1",                    AmountError::Invalid("--1".to_string())),
+            ("1.00005",                AmountError::TooPrecise("1.00005".to_string())),
+            ("922337203685477.5808",   AmountError::Overflow("922337203685477.5808".to_string())),
+            ("99999999999999999999",   AmountError::Overflow("99999999999999999999".to_string())),
+        ];
+
+        for (text, error) in case_list.iter() {
+            assert_eq!(text.parse::<Amount>().as_ref(), Err(error), "Parsing: {:?}", text);
+        }
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for raw in [0, 1, -1, 9_999, 12_345, -50_000, i64::MAX, i64::MIN + 1].iter() {
+            let amount = Amount(*raw);
+            let text = amount.to_string();
+
+            assert_eq!(text.split('.').nth(1).map(str::len), Some(DECIMALS), "Decimals of: {}", text);
+            assert_eq!(text.parse::<Amount>(), Ok(amount), "Parsing back: {}", text);
+        }
+    }
+}
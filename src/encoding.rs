@@ -0,0 +1,510 @@
+/*
+ *  Character encoding of the input files. Inputs are transcoded to UTF-8 while they are read
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+
+/// Size of the blocks read from the input
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Byte written instead of a character that can not be decoded. It is never valid
+/// in UTF-8, so the row that contains it is rejected when it is read
+const INVALID_MARKER: u8 = 0xFF;
+
+/// Characters of Windows-1252 from 0x80 to 0x9F. Zero means undefined
+const WINDOWS_1252_HIGH: [u16; 32] = [
+    0x20AC, 0,      0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021,
+    0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0,      0x017D, 0,
+    0,      0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+    0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0,      0x017E, 0x0178,
+];
+
+
+/**
+ * Supported character encodings
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+    Latin1,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8        => "utf-8",
+            Encoding::Utf16Le     => "utf-16le",
+            Encoding::Utf16Be     => "utf-16be",
+            Encoding::Windows1252 => "windows-1252",
+            Encoding::Latin1      => "latin-1",
+        }
+    }
+
+    /**
+     * Guess the encoding from the first bytes of a file. It returns the encoding and
+     * the size of its byte order mark, if any. Without BOM, UTF-16 is detected by the
+     * zero bytes of ASCII characters. Otherwise UTF-8 is assumed
+     */
+    pub fn detect(in_start: &[u8]) -> (Encoding, usize) {
+        match in_start {
+            [0xEF, 0xBB, 0xBF, ..]                 => (Encoding::Utf8, 3),
+            [0xFF, 0xFE, ..]                       => (Encoding::Utf16Le, 2),
+            [0xFE, 0xFF, ..]                       => (Encoding::Utf16Be, 2),
+            [a, 0, b, 0, ..] if *a != 0 && *b != 0 => (Encoding::Utf16Le, 0),
+            [0, a, 0, b, ..] if *a != 0 && *b != 0 => (Encoding::Utf16Be, 0),
+            _                                      => (Encoding::Utf8, 0),
+        }
+    }
+
+    /**
+     * Size of the byte order mark of this encoding at the start of a file, if any
+     */
+    fn bom_size(self, in_start: &[u8]) -> usize {
+        match (self, in_start) {
+            (Encoding::Utf8,    [0xEF, 0xBB, 0xBF, ..]) => 3,
+            (Encoding::Utf16Le, [0xFF, 0xFE, ..])       => 2,
+            (Encoding::Utf16Be, [0xFE, 0xFF, ..])       => 2,
+            _                                           => 0,
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(in_text: &str) -> Result<Self, Self::Err> {
+        match in_text.to_ascii_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8"                    => Ok(Encoding::Utf8),
+            "utf-16le" | "utf16le"              => Ok(Encoding::Utf16Le),
+            "utf-16be" | "utf16be"              => Ok(Encoding::Utf16Be),
+            "windows-1252" | "cp1252"           => Ok(Encoding::Windows1252),
+            "latin-1" | "latin1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            _ => Err( format!("Unknown encoding: {}", in_text) ),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Reader that transcodes its input to UTF-8. The byte order mark is removed.
+ *
+ * Characters that can not be decoded are replaced by a byte that is not valid UTF-8,
+ * so only the row that contains them is rejected, not the whole file
+ */
+pub struct DecodingReader<R: Read> {
+    inner:          R,
+    // Declared or detected encoding. None until the first bytes are read, if not declared
+    encoding:       Option<Encoding>,
+    bom_size:       usize,
+    started:        bool,
+    eof:            bool,
+    // Bytes read but not decoded yet, i.e. the first byte of a UTF-16 unit
+    input:          Vec<u8>,
+    // UTF-16 high surrogate waiting for its low surrogate
+    high_surrogate: Option<u16>,
+    output:         Vec<u8>,
+    output_pos:     usize,
+}
+
+impl<R: Read> DecodingReader<R> {
+    /**
+     * If no encoding is given, it is detected from the first bytes
+     */
+    pub fn new(in_inner: R, in_encoding: Option<Encoding>) -> Self {
+        DecodingReader {
+            inner:          in_inner,
+            encoding:       in_encoding,
+            bom_size:       0,
+            started:        false,
+            eof:            false,
+            input:          Vec::with_capacity(CHUNK_SIZE),
+            high_surrogate: None,
+            output:         Vec::with_capacity(CHUNK_SIZE),
+            output_pos:     0,
+        }
+    }
+
+    /**
+     * Encoding of the input. It is only known after the first read if it was not declared
+     */
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    /**
+     * Read the next block of the input and append it to the pending bytes
+     */
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let start = self.input.len();
+        self.input.resize(start + CHUNK_SIZE, 0);
+
+        let size = loop {
+            match self.inner.read(&mut self.input[start..]) {
+                Ok(n)  => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.input.truncate(start);
+                    return Err(e);
+                },
+            }
+        };
+
+        self.input.truncate(start + size);
+        if size == 0 {
+            self.eof = true;
+        }
+        Ok(())
+    }
+
+    /**
+     * Read enough bytes to find the byte order mark, decide the encoding and remove the mark
+     */
+    fn start(&mut self) -> io::Result<()> {
+        while self.input.len() < 4 && !self.eof {
+            self.read_chunk()?;
+        }
+
+        let (encoding, bom_size) = match self.encoding {
+            Some(e) => (e, e.bom_size(&self.input)),
+            None    => Encoding::detect(&self.input),
+        };
+        self.encoding = Some(encoding);
+        self.bom_size = bom_size;
+        self.input.drain(..bom_size);
+        self.started = true;
+        Ok(())
+    }
+
+    /**
+     * Transcode the pending input bytes into the output buffer
+     */
+    fn decode(&mut self) {
+        self.output.clear();
+        self.output_pos = 0;
+
+        match self.encoding.unwrap_or(Encoding::Utf8) {
+            Encoding::Utf8 => {
+                self.output.append(&mut self.input);
+            },
+            Encoding::Latin1 => {
+                for &b in &self.input {
+                    push_char(&mut self.output, u32::from(b));
+                }
+                self.input.clear();
+            },
+            Encoding::Windows1252 => {
+                for &b in &self.input {
+                    let c = match b {
+                        0x80..=0x9F => u32::from(WINDOWS_1252_HIGH[usize::from(b - 0x80)]),
+                        _           => u32::from(b),
+                    };
+                    if c == 0 {
+                        self.output.push(INVALID_MARKER);
+                    } else {
+                        push_char(&mut self.output, c);
+                    }
+                }
+                self.input.clear();
+            },
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let big_endian = self.encoding == Some(Encoding::Utf16Be);
+                let complete = self.input.len() - self.input.len() % 2;
+
+                for pair in self.input[..complete].chunks_exact(2) {
+                    let unit = if big_endian {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    };
+                    push_utf16_unit(&mut self.output, &mut self.high_surrogate, unit);
+                }
+                self.input.drain(..complete);
+
+                if self.eof {
+                    // Incomplete unit or surrogate pair at the end of the file
+                    if !self.input.is_empty() || self.high_surrogate.is_some() {
+                        self.output.push(INVALID_MARKER);
+                    }
+                    self.input.clear();
+                    self.high_surrogate = None;
+                }
+            },
+        }
+    }
+}
+
+impl<R: Read + Seek> DecodingReader<R> {
+    /**
+     * Continue at a position of the decoded text, i.e. where an interrupted run stopped.
+     * In UTF-8 it is the position in the file after the byte order mark, so the file is
+     * seeked. Other encodings are decoded up to the position, as their sizes differ
+     */
+    pub fn seek_decoded(&mut self, in_offset: u64) -> io::Result<()> {
+        if !self.started {
+            self.start()?;
+        }
+
+        if self.encoding == Some(Encoding::Utf8) {
+            self.inner.seek(SeekFrom::Start(self.bom_size as u64 + in_offset))?;
+            self.input.clear();
+            self.output.clear();
+            self.output_pos = 0;
+            self.eof = false;
+            return Ok(());
+        }
+
+        let skipped = io::copy(&mut self.by_ref().take(in_offset), &mut io::sink())?;
+        if skipped < in_offset {
+            return Err( io::Error::new(io::ErrorKind::UnexpectedEof, "the input ends before the position") );
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, out_buffer: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            self.start()?;
+        }
+
+        while self.output_pos >= self.output.len() {
+            if self.eof && self.input.is_empty() {
+                return Ok(0);
+            }
+            if !self.eof {
+                self.read_chunk()?;
+            }
+            self.decode();
+        }
+
+        let size = out_buffer.len().min(self.output.len() - self.output_pos);
+        out_buffer[..size].copy_from_slice(&self.output[self.output_pos..self.output_pos + size]);
+        self.output_pos += size;
+        Ok(size)
+    }
+}
+
+/**
+ * Append a character as UTF-8
+ */
+fn push_char(out_buffer: &mut Vec<u8>, in_code: u32) {
+    match char::from_u32(in_code) {
+        Some(c) => {
+            let mut encoded = [0; 4];
+            out_buffer.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
+        },
+        None => out_buffer.push(INVALID_MARKER),
+    }
+}
+
+/**
+ * Append a UTF-16 unit as UTF-8. Surrogate pairs may be split between two reads, so
+ * a high surrogate is kept until the next unit arrives
+ */
+fn push_utf16_unit(out_buffer: &mut Vec<u8>, io_high_surrogate: &mut Option<u16>, in_unit: u16) {
+    match (io_high_surrogate.take(), in_unit) {
+        (Some(high), 0xDC00..=0xDFFF) => {
+            let code = 0x10000 + ((u32::from(high) - 0xD800) << 10) + (u32::from(in_unit) - 0xDC00);
+            push_char(out_buffer, code);
+        },
+        (previous, _) => {
+            if previous.is_some() {
+                // High surrogate without low surrogate
+                out_buffer.push(INVALID_MARKER);
+            }
+
+            match in_unit {
+                0xD800..=0xDBFF => *io_high_surrogate = Some(in_unit),
+                0xDC00..=0xDFFF => out_buffer.push(INVALID_MARKER),
+                _               => push_char(out_buffer, u32::from(in_unit)),
+            }
+        },
+    }
+}
+
+// ---------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Text with characters of 1, 2, 3 and 4 bytes in UTF-8, the last one a surrogate pair in UTF-16
+    const TEXT: &str = "type,client\ndépôsit,€1\n😀,2\n";
+
+    /**
+     * Reader that returns at most a few bytes on each read, so characters and
+     * byte order marks are split between reads
+     */
+    struct SmallReads<'a> {
+        data:     &'a [u8],
+        size:     usize,
+    }
+
+    impl<'a> Read for SmallReads<'a> {
+        fn read(&mut self, out_buffer: &mut [u8]) -> io::Result<usize> {
+            let size = self.size.min(out_buffer.len()).min(self.data.len());
+            out_buffer[..size].copy_from_slice(&self.data[..size]);
+            self.data = &self.data[size..];
+            Ok(size)
+        }
+    }
+
+    fn utf16(in_text: &str, in_big_endian: bool, in_bom: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let bom = if in_bom { Some(0xFEFF) } else { None };
+
+        for unit in bom.into_iter().chain(in_text.encode_utf16()) {
+            if in_big_endian {
+                bytes.extend_from_slice(&unit.to_be_bytes());
+            } else {
+                bytes.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /**
+     * Decode the data, read in pieces of the given size and into a buffer of the given size
+     */
+    fn decode_with(in_data: &[u8], in_encoding: Option<Encoding>, in_read_size: usize, in_buffer_size: usize) -> Vec<u8> {
+        let mut reader = DecodingReader::new(SmallReads { data: in_data, size: in_read_size }, in_encoding);
+        let mut decoded = Vec::new();
+        let mut buffer = vec![0; in_buffer_size];
+
+        loop {
+            let size = reader.read(&mut buffer).expect("Reading");
+            if size == 0 {
+                return decoded;
+            }
+            decoded.extend_from_slice(&buffer[..size]);
+        }
+    }
+
+    fn decode(in_data: &[u8], in_encoding: Option<Encoding>) -> Vec<u8> {
+        decode_with(in_data, in_encoding, CHUNK_SIZE, CHUNK_SIZE)
+    }
+
+    #[test]
+    fn detect() {
+        let case_list: [(&[u8], (Encoding, usize)); 10] = [
+            (b"",                          (Encoding::Utf8,    0)),
+            (b"t",                         (Encoding::Utf8,    0)),
+            (b"type,client",               (Encoding::Utf8,    0)),
+            (b"\xEF\xBB\xBFtype",          (Encoding::Utf8,    3)),
+            (b"\xFF\xFEt\x00y\x00",        (Encoding::Utf16Le, 2)),
+            (b"\xFE\xFF\x00t\x00y",        (Encoding::Utf16Be, 2)),
+            (b"t\x00y\x00",                (Encoding::Utf16Le, 0)),
+            (b"\x00t\x00y",                (Encoding::Utf16Be, 0)),
+            (b"\xFF\xFE",                  (Encoding::Utf16Le, 2)),
+            (b"t\x00\x00\x00",             (Encoding::Utf8,    0)),
+        ];
+
+        for (start, expected) in case_list.iter() {
+            assert_eq!(Encoding::detect(start), *expected, "Detecting: {:?}", start);
+        }
+    }
+
+    #[test]
+    fn decode_all_encodings() {
+        assert_eq!(decode(TEXT.as_bytes(), None), TEXT.as_bytes());
+        assert_eq!(decode(&[b"\xEF\xBB\xBF", TEXT.as_bytes()].concat(), None), TEXT.as_bytes());
+        assert_eq!(decode(&utf16(TEXT, false, true), None), TEXT.as_bytes());
+        assert_eq!(decode(&utf16(TEXT, true, true), None), TEXT.as_bytes());
+        assert_eq!(decode(&utf16(TEXT, false, false), None), TEXT.as_bytes());
+        assert_eq!(decode(&utf16(TEXT, true, false), Some(Encoding::Utf16Be)), TEXT.as_bytes());
+
+        assert_eq!(decode(b"d\xE9p\xF4t \x80\x99", Some(Encoding::Windows1252)), "dépôt €™".as_bytes());
+        assert_eq!(decode(b"d\xE9p\xF4t \x80", Some(Encoding::Latin1)), "dépôt \u{80}".as_bytes());
+    }
+
+    #[test]
+    fn declared_encoding_removes_only_its_bom() {
+        assert_eq!(decode(&utf16("a,b", false, true), Some(Encoding::Utf16Le)), b"a,b");
+        assert_eq!(decode(b"\xEF\xBB\xBFa,b", Some(Encoding::Utf8)), b"a,b");
+        assert_eq!(decode(b"\xEF\xBB\xBFa", Some(Encoding::Latin1)), "ï»¿a".as_bytes());
+    }
+
+    #[test]
+    fn odd_sized_reads() {
+        let input_list = [
+            (TEXT.as_bytes().to_vec(),                               None),
+            ([b"\xEF\xBB\xBF", TEXT.as_bytes()].concat(),            None),
+            (utf16(TEXT, false, true),                               None),
+            (utf16(TEXT, true, true),                                None),
+            (utf16(TEXT, false, false),                              Some(Encoding::Utf16Le)),
+        ];
+
+        for (data, encoding) in input_list.iter() {
+            for read_size in 1..=7 {
+                for buffer_size in [1, 2, 3, 5, 64].iter() {
+                    assert_eq!(decode_with(data, *encoding, read_size, *buffer_size), TEXT.as_bytes(),
+                               "Reads of {} bytes into {} bytes of {:?}", read_size, buffer_size, encoding);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_marker() {
+        // Undefined characters of Windows-1252
+        assert_eq!(decode(b"a\x81b\x9Dc", Some(Encoding::Windows1252)), b"a\xFFb\xFFc");
+
+        // Low surrogate without high surrogate, and high surrogate followed by another character
+        assert_eq!(decode(&[0x61, 0x00, 0x00, 0xDC, 0x62, 0x00], Some(Encoding::Utf16Le)), b"a\xFFb");
+        assert_eq!(decode(&[0x61, 0x00, 0x3D, 0xD8, 0x62, 0x00], Some(Encoding::Utf16Le)), b"a\xFFb");
+
+        // Incomplete unit, and high surrogate at the end of the file
+        assert_eq!(decode(&[0x61, 0x00, 0x62], Some(Encoding::Utf16Le)), b"a\xFF");
+        assert_eq!(decode(&[0x61, 0x00, 0x3D, 0xD8], Some(Encoding::Utf16Le)), b"a\xFF");
+
+        // Invalid UTF-8 is kept as it is
+        assert_eq!(decode(b"a\xC3b", None), b"a\xC3b");
+
+        for read_size in 1..=3 {
+            assert_eq!(decode_with(&[0x61, 0x00, 0x00, 0xDC, 0x62], Some(Encoding::Utf16Le), read_size, 1), b"a\xFF\xFF",
+                       "Reads of {} bytes", read_size);
+        }
+        assert!(std::str::from_utf8(&[INVALID_MARKER]).is_err());
+    }
+
+    #[test]
+    fn seek_decoded() {
+        let position = TEXT.find('\n').unwrap() + 1;
+        let input_list = [
+            TEXT.as_bytes().to_vec(),
+            [b"\xEF\xBB\xBF", TEXT.as_bytes()].concat(),
+            utf16(TEXT, false, true),
+            utf16(TEXT, true, false),
+        ];
+
+        for data in input_list.iter() {
+            let mut reader = DecodingReader::new(Cursor::new(data), None);
+            let mut rest = String::new();
+
+            reader.seek_decoded(position as u64).expect("Seeking");
+            reader.read_to_string(&mut rest).expect("Reading");
+            assert_eq!(rest, &TEXT[position..]);
+        }
+
+        let mut reader = DecodingReader::new(Cursor::new(utf16(TEXT, false, true)), None);
+        assert!(reader.seek_decoded(TEXT.len() as u64 + 1).is_err());
+    }
+}
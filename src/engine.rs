@@ -0,0 +1,488 @@
+/*
+ *  Payment engine. It applies transactions to the client accounts
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use csv::ByteRecord;
+
+use crate::amount::Amount;
+use crate::error::EngineError;
+use crate::state;
+use crate::store::{MemoryStore, StoreState, StoredDeposit, StoredTransaction, TransactionState, TransactionStore};
+use crate::transaction::{ClientAccount, QueuedTransaction, Transaction, TransactionKind};
+
+
+/**
+ * What to do with the operations of a locked account
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockedPolicy {
+    // Reject them
+    Reject,
+    // Keep them and apply them, in order, when the account is unlocked
+    Queue,
+}
+
+/**
+ * Result of an operation queued on a locked account, applied when the account is unlocked
+ */
+#[derive(Debug, Clone)]
+pub struct ReplayedTransaction {
+    pub queued:    QueuedTransaction,
+    pub result:    Result<(), EngineError>,
+}
+
+/**
+ * Row a transaction was read from: the input, the line, and the fields with the columns
+ * of the input. It is only copied if the transaction is queued
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SourceRow<'a> {
+    pub source:    &'a str,
+    pub line:      u64,
+    pub columns:   &'a ByteRecord,
+    pub record:    &'a ByteRecord,
+}
+
+/**
+ * Payment engine. It owns the client accounts and the store of transactions that can be
+ * referenced by later disputes. By default, the store is kept in memory
+ */
+#[derive(Debug)]
+pub struct PaymentEngine {
+    client_list:       HashMap<u16, ClientAccount>,
+    transaction_list:  Box<dyn TransactionStore>,
+    locked_policy:     LockedPolicy,
+}
+
+impl PaymentEngine {
+    pub fn new() -> Self {
+        PaymentEngine::with_locked_policy(LockedPolicy::Reject)
+    }
+
+    pub fn with_locked_policy(in_locked_policy: LockedPolicy) -> Self {
+        PaymentEngine::with_store(in_locked_policy, Box::new(MemoryStore::new()))
+    }
+
+    /**
+     * Engine that keeps the transactions in the given store, i.e. a `DiskStore`
+     */
+    pub fn with_store(in_locked_policy: LockedPolicy, in_store: Box<dyn TransactionStore>) -> Self {
+        PaymentEngine {
+            client_list:       HashMap::new(),
+            transaction_list:  in_store,
+            locked_policy:     in_locked_policy,
+        }
+    }
+
+    /**
+     * Engine with the given accounts and transaction store, i.e. taken from other engines
+     */
+    pub fn from_parts(in_locked_policy: LockedPolicy, in_accounts: Vec<ClientAccount>, in_store: Box<dyn TransactionStore>) -> Self {
+        let mut engine = PaymentEngine::with_store(in_locked_policy, in_store);
+        engine.client_list = in_accounts.into_iter().map(|c| (c.client_id, c)).collect();
+        engine
+    }
+
+    /**
+     * Take the accounts, with their queued operations, and the transaction store out of
+     * the engine, i.e. to split the clients between several engines
+     */
+    pub fn into_parts(self) -> (Vec<ClientAccount>, Box<dyn TransactionStore>) {
+        (self.client_list.into_values().collect(), self.transaction_list)
+    }
+
+    pub fn locked_policy(&self) -> LockedPolicy {
+        self.locked_policy
+    }
+
+    /**
+     * Make sure the transaction store is saved
+     */
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.transaction_list.flush()
+    }
+
+    /**
+     * Make the changes of the transaction store permanent. It shall be called after the
+     * state is saved, so an aborted run can be undone
+     */
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.transaction_list.commit()
+    }
+
+    /**
+     * Save the accounts and the stored transactions, so a later run can continue the
+     * same ledger. The transactions are not saved if the store keeps its own file
+     */
+    pub fn save_state<W: Write>(&mut self, in_writer: W) -> io::Result<()> {
+        state::save_state(in_writer, &self.client_list, self.transaction_list.as_mut())
+    }
+
+    /**
+     * Load the accounts and the stored transactions of a previous run. It shall be called
+     * before applying any transaction
+     */
+    pub fn load_state<R: Read>(&mut self, in_reader: R) -> io::Result<()> {
+        self.client_list = state::load_state(in_reader, self.transaction_list.as_mut())?;
+        Ok(())
+    }
+
+    /**
+     * Start a new ledger, without the state of previous runs. It fails if the transaction
+     * store keeps the transactions of other runs
+     */
+    pub fn start_ledger(&mut self) -> io::Result<()> {
+        self.transaction_list.recover(None)
+    }
+
+    /**
+     * Save the accounts only, in the format of `save_state()`. The transactions are saved
+     * apart, i.e. the changes recorded by a `RecordingStore`
+     */
+    pub fn save_accounts<W: Write>(&self, in_writer: W) -> io::Result<()> {
+        state::save_accounts(in_writer, &self.client_list)
+    }
+
+    /**
+     * Load the accounts saved by `save_accounts()` or `save_state()`. The transactions,
+     * if any, are not loaded
+     */
+    pub fn load_accounts<R: Read>(&mut self, in_reader: R) -> io::Result<()> {
+        self.client_list = state::load_accounts(in_reader)?;
+        Ok(())
+    }
+
+    /**
+     * Add transactions to the store, i.e. the changes recorded by a `RecordingStore`
+     */
+    pub fn import_transactions(&mut self, in_transactions: &StoreState) -> io::Result<()> {
+        self.transaction_list.import(in_transactions)
+    }
+
+    /**
+     * Apply a transaction and update the client's account. If the transaction is 
+     * rejected, the accounts are not modified.
+     *
+     * An 'unlock' applies the operations queued while the account was locked. It returns
+     * the result of each one, and it succeeds even if some of them are rejected
+     */
+    pub fn apply(&mut self, in_tx: &Transaction) -> Result<Vec<ReplayedTransaction>, EngineError> {
+        let empty = ByteRecord::new();
+        self.apply_from(in_tx, &SourceRow { source: "", line: 0, columns: &empty, record: &empty })
+    }
+
+    /**
+     * Same as `apply()`, for a transaction read from the given row of an input. The row is
+     * kept with the transaction if it is queued, so it can be reported when it is replayed
+     */
+    pub fn apply_from(&mut self, in_tx: &Transaction, in_row: &SourceRow) -> Result<Vec<ReplayedTransaction>, EngineError> {
+        process_transaction(in_tx, in_row, &mut self.client_list, self.transaction_list.as_mut(), self.locked_policy)
+    }
+
+    /**
+     * Account of a client, if it has any transaction
+     */
+    pub fn account(&self, in_client_id: u16) -> Option<&ClientAccount> {
+        self.client_list.get(&in_client_id)
+    }
+
+    /**
+     * All the client accounts, in no particular order
+     */
+    pub fn accounts(&self) -> impl Iterator<Item = &ClientAccount> {
+        self.client_list.values()
+    }
+
+    /**
+     * Copy of the current client accounts, ordered by client id
+     */
+    pub fn snapshot(&self) -> Vec<ClientAccount> {
+        let mut accounts: Vec<ClientAccount> = self.client_list.values().cloned().collect();
+        accounts.sort_by_key(|c| c.client_id);
+        accounts
+    }
+}
+
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        PaymentEngine::new()
+    }
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Add an amount to a client's balance. It fails if the result overflows
+ */
+fn add_funds(in_current_tx: &Transaction, in_balance: Amount, in_amount: Amount) -> Result<Amount, EngineError> {
+    in_balance.checked_add(in_amount)
+              .ok_or(EngineError::Overflow { client_id: in_current_tx.client_id, tx_id: in_current_tx.tx_id })
+}
+
+/**
+ * Subtract an amount from a client's balance. It fails if the result overflows
+ */
+fn sub_funds(in_current_tx: &Transaction, in_balance: Amount, in_amount: Amount) -> Result<Amount, EngineError> {
+    in_balance.checked_sub(in_amount)
+              .ok_or(EngineError::Overflow { client_id: in_current_tx.client_id, tx_id: in_current_tx.tx_id })
+}
+
+/**
+ * Copy of a client's account, or a new one if it does not exist. It is only added to
+ * the list when the transaction is applied, so rejected transactions do not create accounts
+ */
+fn get_client(in_id: u16, in_client_list: &HashMap<u16, ClientAccount>) -> ClientAccount {
+    in_client_list.get(&in_id)
+                  .cloned()
+                  .unwrap_or_else(|| ClientAccount::new(in_id))
+}
+
+fn storage_error(in_current_tx: &Transaction, in_error: io::Error) -> EngineError {
+    EngineError::Storage { client_id: in_current_tx.client_id, tx_id: in_current_tx.tx_id, message: in_error.to_string() }
+}
+
+/**
+ * Register the id of a deposit or withdrawal. It fails if it has already been used
+ */
+fn add_id(in_current_tx: &Transaction, in_transaction_list: &mut dyn TransactionStore) -> Result<(), EngineError> {
+    let is_new = in_transaction_list.add_id(in_current_tx.tx_id)
+                                    .map_err(|e| storage_error(in_current_tx, e))?;
+    if !is_new {
+        return Err( EngineError::DuplicateTx { client_id: in_current_tx.client_id, tx_id: in_current_tx.tx_id } );
+    }
+    Ok(())
+}
+
+fn put_deposit(in_current_tx: &Transaction, in_transaction_list: &mut dyn TransactionStore, in_deposit: StoredDeposit) -> Result<(), EngineError> {
+    in_transaction_list.put_deposit(in_current_tx.tx_id, in_deposit)
+                       .map_err(|e| storage_error(in_current_tx, e))
+}
+
+/**
+ * Search the deposit referenced by a dispute, resolve or chargeback.
+ * It fails if it does not exist, it is not a deposit, it is no longer kept or
+ * it belongs to a different client
+ */
+fn get_deposit(in_current_tx: &Transaction, in_transaction_list: &mut dyn TransactionStore) -> Result<StoredDeposit, EngineError> {
+    let client_id = in_current_tx.client_id;
+    let tx_id     = in_current_tx.tx_id;
+
+    let found = in_transaction_list.get(tx_id)
+                                   .map_err(|e| storage_error(in_current_tx, e))?;
+    match found {
+        Some(StoredTransaction::Deposit(d)) if d.client_id != client_id => Err( EngineError::ClientMismatch { client_id, tx_id, owner_id: d.client_id } ),
+        Some(StoredTransaction::Deposit(d))                             => Ok(d),
+        Some(StoredTransaction::IdOnly)                                 => Err( EngineError::NotDisputable { client_id, tx_id } ),
+        None                                                            => Err( EngineError::UnknownTx { client_id, tx_id } ),
+    }
+}
+
+/**
+ * Amount of a deposit or withdrawal. It fails if the row does not have one or it is not positive
+ */
+fn required_amount(in_current_tx: &Transaction) -> Result<Amount, EngineError> {
+    match in_current_tx.amount {
+        Some(a) if a > Amount::ZERO => Ok(a),
+        Some(a) => Err( EngineError::InvalidAmount { client_id: in_current_tx.client_id, tx_id: in_current_tx.tx_id, amount: a } ),
+        None    => Err( EngineError::MissingAmount { client_id: in_current_tx.client_id, tx_id: in_current_tx.tx_id, kind: in_current_tx.kind } ),
+    }
+}
+
+/**
+ * Move a stored deposit to a new state. It fails if the transition is not allowed
+ */
+fn change_state(in_current_tx: &Transaction, in_stored_tx: &mut StoredDeposit, in_new: TransactionState) -> Result<(), EngineError> {
+    let client_id = in_current_tx.client_id;
+    let tx_id     = in_current_tx.tx_id;
+
+    match (in_stored_tx.state, in_new) {
+        (TransactionState::Normal,      TransactionState::Disputed) => {},
+        (TransactionState::ChargedBack, TransactionState::Disputed) => return Err( EngineError::AlreadyChargedBack { client_id, tx_id } ),
+        (_,                             TransactionState::Disputed) => return Err( EngineError::AlreadyDisputed { client_id, tx_id } ),
+        (TransactionState::Disputed,    _)                          => {},
+        (_,                             _)                          => return Err( EngineError::NotDisputed { client_id, tx_id } ),
+    }
+
+    in_stored_tx.state = in_new;
+    Ok(())
+}
+
+/**
+ * Process a transaction and update clientś account
+ * 
+ * Dispute, resolve and chargeback reference a previous deposit by its transaction id.
+ * They are not stored, they only change the state of the referenced deposit
+ *
+ * Once an account is locked, all its operations are rejected or queued, depending on
+ * the policy, until an 'unlock' transaction is received
+ */
+fn process_transaction(in_current_tx: &Transaction, in_row: &SourceRow, in_client_list: &mut HashMap<u16, ClientAccount>,
+                       in_transaction_list: &mut dyn TransactionStore, in_locked_policy: LockedPolicy) -> Result<Vec<ReplayedTransaction>, EngineError> {
+    let client_id = in_current_tx.client_id;
+
+    if in_current_tx.kind != TransactionKind::Unlock {
+        if let Some(c) = in_client_list.get_mut(&client_id) {
+            if c.locked {
+                match in_locked_policy {
+                    LockedPolicy::Reject => {
+                        return Err( EngineError::AccountLocked { client_id, tx_id: in_current_tx.tx_id } );
+                    },
+                    LockedPolicy::Queue => {
+                        c.pending.push(QueuedTransaction {
+                            tx:       in_current_tx.clone(),
+                            source:   in_row.source.to_string(),
+                            line:     in_row.line,
+                            columns:  in_row.columns.clone(),
+                            record:   in_row.record.clone(),
+                        });
+                        return Ok(Vec::new());
+                    },
+                }
+            }
+        }
+    }
+
+    match in_current_tx.kind {
+        // -------------------------------------
+        TransactionKind::Deposit => {
+            let amount = required_amount(in_current_tx)?;
+
+            // Search for client
+            let mut the_client = get_client(client_id, in_client_list);
+
+            // Increase available and total funds of client
+            the_client.available = add_funds(in_current_tx, the_client.available, amount)?;
+            the_client.total     = add_funds(in_current_tx, the_client.total,     amount)?;
+
+            // Keep the deposit, so it can be disputed
+            add_id(in_current_tx, in_transaction_list)?;
+            put_deposit(in_current_tx, in_transaction_list, StoredDeposit {
+                client_id,
+                amount,
+                state:      TransactionState::Normal,
+            })?;
+
+            // Update the client
+            in_client_list.insert(client_id, the_client);
+        },
+
+        // -------------------------------------
+        TransactionKind::Withdrawal => {
+            let amount = required_amount(in_current_tx)?;
+
+            // Search for client
+            let mut the_client = get_client(client_id, in_client_list);
+
+            if the_client.available >= amount {
+                // Decrease available and total funds of client
+                the_client.available = sub_funds(in_current_tx, the_client.available, amount)?;
+                the_client.total     = sub_funds(in_current_tx, the_client.total,     amount)?;
+            } else {
+                return Err( EngineError::InsufficientFunds { client_id, tx_id: in_current_tx.tx_id, available: the_client.available, requested: amount } );
+            }
+
+            // Only the id is kept, so it can not be reused. Withdrawals can not be disputed
+            add_id(in_current_tx, in_transaction_list)?;
+
+            // Update the client
+            in_client_list.insert(client_id, the_client);
+        },
+
+        // -------------------------------------
+        TransactionKind::Dispute => {
+            // Get the deposit. It shall exist and belong to the same client
+            let mut p = get_deposit(in_current_tx, in_transaction_list)?;
+            let stored_amount = p.amount;
+
+            // Search for client
+            let mut the_client = get_client(client_id, in_client_list);
+
+            // Decrease client available fnds and increase held funds
+            the_client.available = sub_funds(in_current_tx, the_client.available, stored_amount)?;
+            the_client.held      = add_funds(in_current_tx, the_client.held,      stored_amount)?;
+
+            change_state(in_current_tx, &mut p, TransactionState::Disputed)?;
+            put_deposit(in_current_tx, in_transaction_list, p)?;
+
+            // Update the client
+            in_client_list.insert(client_id, the_client);
+        },
+
+        // -------------------------------------
+        TransactionKind::Resolve => {
+            // Get the deposit. It shall exist and belong to the same client
+            let mut p = get_deposit(in_current_tx, in_transaction_list)?;
+            let stored_amount = p.amount;
+
+            // Search for client
+            let mut the_client = get_client(client_id, in_client_list);
+
+            // Decrease client held funds and increase the available funds
+            the_client.available = add_funds(in_current_tx, the_client.available, stored_amount)?;
+            the_client.held      = sub_funds(in_current_tx, the_client.held,      stored_amount)?;
+
+            // Previous transaction shall be under dispute
+            change_state(in_current_tx, &mut p, TransactionState::Resolved)?;
+            put_deposit(in_current_tx, in_transaction_list, p)?;
+
+            // Update the client
+            in_client_list.insert(client_id, the_client);
+        },
+
+        // -------------------------------------
+        TransactionKind::Chargeback => {
+            // Get the deposit. It shall exist and belong to the same client
+            let mut p = get_deposit(in_current_tx, in_transaction_list)?;
+            let stored_amount = p.amount;
+
+            // Search for client
+            let mut the_client = get_client(client_id, in_client_list);
+
+            // Decrease client held and total funds
+            the_client.held      = sub_funds(in_current_tx, the_client.held,  stored_amount)?;
+            the_client.total     = sub_funds(in_current_tx, the_client.total, stored_amount)?;
+            // Lock the account
+            the_client.locked     = true;
+
+            // Previous transaction shall be under dispute
+            change_state(in_current_tx, &mut p, TransactionState::ChargedBack)?;
+            put_deposit(in_current_tx, in_transaction_list, p)?;
+
+            // Update the client
+            in_client_list.insert(client_id, the_client);
+        },
+
+        // -------------------------------------
+        TransactionKind::Unlock => {
+            let queued_list = match in_client_list.get_mut(&client_id) {
+                Some(c) if c.locked => {
+                    c.locked = false;
+                    std::mem::take(&mut c.pending)
+                },
+                Some(_) => return Err( EngineError::NotLocked { client_id, tx_id: in_current_tx.tx_id } ),
+                None    => return Err( EngineError::UnknownClient { client_id, tx_id: in_current_tx.tx_id } ),
+            };
+
+            // Apply the operations queued while the account was locked. If one of them
+            // locks the account again, the rest are queued again
+            let replayed_list = queued_list.into_iter()
+                                           .map(|q| {
+                                               let row = SourceRow { source: &q.source, line: q.line, columns: &q.columns, record: &q.record };
+                                               let result = process_transaction(&q.tx, &row, in_client_list, in_transaction_list, in_locked_policy)
+                                                               .map(|_| ());
+                                               ReplayedTransaction { queued: q, result }
+                                           })
+                                           .collect();
+            return Ok(replayed_list);
+        },
+    }
+
+    Ok(Vec::new())
+}
@@ -0,0 +1,176 @@
+/*
+ *  Errors returned by the payment engine
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::fmt;
+
+use crate::amount::Amount;
+use crate::transaction::TransactionKind;
+
+
+/**
+ * Reason why the engine rejects a transaction. A rejected transaction does not modify
+ * the accounts. Each kind has a stable code, see `code()`
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    /// Withdrawal bigger than the available funds
+    InsufficientFunds { client_id: u16, tx_id: u32, available: Amount, requested: Amount },
+    /// Deposit or withdrawal with a transaction id already used
+    DuplicateTx { client_id: u16, tx_id: u32 },
+    /// Dispute, resolve or chargeback of a transaction that does not exist
+    UnknownTx { client_id: u16, tx_id: u32 },
+    /// Operation on a locked account
+    AccountLocked { client_id: u16, tx_id: u32 },
+    /// Dispute, resolve or chargeback of a withdrawal, or of a deposit no longer kept
+    NotDisputable { client_id: u16, tx_id: u32 },
+    /// Dispute, resolve or chargeback of a transaction of another client
+    ClientMismatch { client_id: u16, tx_id: u32, owner_id: u16 },
+    /// Deposit or withdrawal without amount
+    MissingAmount { client_id: u16, tx_id: u32, kind: TransactionKind },
+    /// Deposit or withdrawal with an amount that is not positive
+    InvalidAmount { client_id: u16, tx_id: u32, amount: Amount },
+    /// Dispute of a transaction that has already been disputed
+    AlreadyDisputed { client_id: u16, tx_id: u32 },
+    /// Dispute of a transaction that has been charged back
+    AlreadyChargedBack { client_id: u16, tx_id: u32 },
+    /// Resolve or chargeback of a transaction that is not under dispute
+    NotDisputed { client_id: u16, tx_id: u32 },
+    /// Unlock of an account that is not locked
+    NotLocked { client_id: u16, tx_id: u32 },
+    /// Unlock of a client without account
+    UnknownClient { client_id: u16, tx_id: u32 },
+    /// Balance out of the range of an `Amount`
+    Overflow { client_id: u16, tx_id: u32 },
+    /// The transaction store can not be read or written. Processing shall not continue
+    Storage { client_id: u16, tx_id: u32, message: String },
+}
+
+impl EngineError {
+    /**
+     * Short stable identifier of the kind of error, i.e. for reports
+     */
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::InsufficientFunds { .. }  => "insufficient_funds",
+            EngineError::DuplicateTx { .. }        => "duplicate_tx",
+            EngineError::UnknownTx { .. }          => "unknown_tx",
+            EngineError::AccountLocked { .. }      => "account_locked",
+            EngineError::NotDisputable { .. }      => "not_disputable",
+            EngineError::ClientMismatch { .. }     => "client_mismatch",
+            EngineError::MissingAmount { .. }      => "missing_amount",
+            EngineError::InvalidAmount { .. }      => "invalid_amount",
+            EngineError::AlreadyDisputed { .. }    => "already_disputed",
+            EngineError::AlreadyChargedBack { .. } => "already_charged_back",
+            EngineError::NotDisputed { .. }        => "not_disputed",
+            EngineError::NotLocked { .. }          => "not_locked",
+            EngineError::UnknownClient { .. }      => "unknown_client",
+            EngineError::Overflow { .. }           => "overflow",
+            EngineError::Storage { .. }            => "storage_error",
+        }
+    }
+
+    /**
+     * Client of the rejected transaction
+     */
+    pub fn client_id(&self) -> u16 {
+        match self {
+            EngineError::InsufficientFunds { client_id, .. }
+            | EngineError::DuplicateTx { client_id, .. }
+            | EngineError::UnknownTx { client_id, .. }
+            | EngineError::AccountLocked { client_id, .. }
+            | EngineError::NotDisputable { client_id, .. }
+            | EngineError::ClientMismatch { client_id, .. }
+            | EngineError::MissingAmount { client_id, .. }
+            | EngineError::InvalidAmount { client_id, .. }
+            | EngineError::AlreadyDisputed { client_id, .. }
+            | EngineError::AlreadyChargedBack { client_id, .. }
+            | EngineError::NotDisputed { client_id, .. }
+            | EngineError::NotLocked { client_id, .. }
+            | EngineError::UnknownClient { client_id, .. }
+            | EngineError::Overflow { client_id, .. }
+            | EngineError::Storage { client_id, .. } => *client_id,
+        }
+    }
+
+    /**
+     * Transaction id of the rejected transaction
+     */
+    pub fn tx_id(&self) -> u32 {
+        match self {
+            EngineError::InsufficientFunds { tx_id, .. }
+            | EngineError::DuplicateTx { tx_id, .. }
+            | EngineError::UnknownTx { tx_id, .. }
+            | EngineError::AccountLocked { tx_id, .. }
+            | EngineError::NotDisputable { tx_id, .. }
+            | EngineError::ClientMismatch { tx_id, .. }
+            | EngineError::MissingAmount { tx_id, .. }
+            | EngineError::InvalidAmount { tx_id, .. }
+            | EngineError::AlreadyDisputed { tx_id, .. }
+            | EngineError::AlreadyChargedBack { tx_id, .. }
+            | EngineError::NotDisputed { tx_id, .. }
+            | EngineError::NotLocked { tx_id, .. }
+            | EngineError::UnknownClient { tx_id, .. }
+            | EngineError::Overflow { tx_id, .. }
+            | EngineError::Storage { tx_id, .. } => *tx_id,
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::InsufficientFunds { client_id, tx_id, available, requested } => {
+                write!(f, "Client: {} has insufficient funds: {} for transaction: {} of: {}", client_id, available, tx_id, requested)
+            },
+            EngineError::DuplicateTx { tx_id, .. } => {
+                write!(f, "Transaction already exists: {}", tx_id)
+            },
+            EngineError::UnknownTx { client_id, tx_id } => {
+                write!(f, "Client: {} references unknown transaction: {}", client_id, tx_id)
+            },
+            EngineError::AccountLocked { client_id, tx_id } => {
+                write!(f, "Client: {} account is locked. Transaction rejected: {}", client_id, tx_id)
+            },
+            EngineError::NotDisputable { tx_id, .. } => {
+                write!(f, "Transaction: {} can not be disputed. It is not a deposit, or it is no longer kept", tx_id)
+            },
+            EngineError::ClientMismatch { client_id, tx_id, owner_id } => {
+                write!(f, "Client mismatch. Transaction: {} belongs to client: {}, not to client: {}", tx_id, owner_id, client_id)
+            },
+            EngineError::MissingAmount { tx_id, kind, .. } => {
+                write!(f, "Transaction: {} of type {} has no amount", tx_id, kind)
+            },
+            EngineError::InvalidAmount { tx_id, amount, .. } => {
+                write!(f, "Transaction: {} has an invalid amount: {}. It shall be positive", tx_id, amount)
+            },
+            EngineError::AlreadyDisputed { tx_id, .. } => {
+                write!(f, "Transaction: {} has already been disputed", tx_id)
+            },
+            EngineError::AlreadyChargedBack { tx_id, .. } => {
+                write!(f, "Transaction: {} has been charged back, it can not be disputed", tx_id)
+            },
+            EngineError::NotDisputed { tx_id, .. } => {
+                write!(f, "Transaction: {} is not under dispute", tx_id)
+            },
+            EngineError::NotLocked { client_id, .. } => {
+                write!(f, "Client: {} account is not locked", client_id)
+            },
+            EngineError::UnknownClient { client_id, .. } => {
+                write!(f, "Unable to find client: {}", client_id)
+            },
+            EngineError::Overflow { client_id, tx_id } => {
+                write!(f, "Client: {} balance overflow applying transaction: {}", client_id, tx_id)
+            },
+            EngineError::Storage { tx_id, message, .. } => {
+                write!(f, "Transaction store failed processing transaction: {}: {}", tx_id, message)
+            },
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
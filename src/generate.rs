@@ -0,0 +1,631 @@
+/*
+ *  Generator of synthetic transaction files, for load tests and regression cases.
+ *  The generator keeps its own ledger, so it can also write the balances that the
+ *  tool shall produce for the generated file
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use csv_payment::{Amount, ClientAccount, KindAliases, LockedPolicy, MemoryStore, PaymentEngine, TransactionKind};
+
+use crate::{write_accounts_file, write_file_atomically, AccountOrder, DataFormat};
+
+
+/// Weights of the transaction types, by default. Chargebacks lock the accounts, so
+/// they are unlocked at the same rate. Otherwise all of them end locked
+const DEFAULT_MIX: [(TransactionKind, u32); 6] = [
+    (TransactionKind::Deposit,    60),
+    (TransactionKind::Withdrawal, 30),
+    (TransactionKind::Dispute,     5),
+    (TransactionKind::Resolve,     3),
+    (TransactionKind::Chargeback,  2),
+    (TransactionKind::Unlock,      2),
+];
+
+/// Attempts to find a client or a transaction that fits a row, before choosing another type
+const MAX_ATTEMPTS: usize = 8;
+
+
+/**
+ * Options of the generate command
+ */
+#[derive(Debug, Clone)]
+pub struct GenerateConfig {
+    clients:       u16,
+    rows:          u64,
+    mix:           Vec<(TransactionKind, u32)>,
+    invalid_ratio: f64,
+    seed:          Option<u64>,
+    output_file:   Option<String>,
+    expected_file: Option<String>,
+}
+
+pub fn usage() {
+    println!("Batch CSV Payment - Transaction generator");
+    println!("Usage:     csv_payment   generate   [options]");
+    println!();
+    println!("Options:");
+    println!("   --clients <N>            - Number of clients, from 1 to 65535. 100 by default");
+    println!("   --rows <N>               - Number of rows. 1000 by default");
+    println!("   --mix <type>=<weight>,...  - Relative weight of each transaction type. By default:");
+    println!("                              deposit=60,withdrawal=30,dispute=5,resolve=3,chargeback=2,unlock=2");
+    println!("                              Types not listed are not generated. Chargebacks lock the accounts");
+    println!("                              and, without unlocks, all of them end locked. Then no more valid");
+    println!("                              rows fit and the generator fails");
+    println!("   --invalid <ratio>        - Ratio of invalid rows, from 0 (default) to 1. I.e. insufficient");
+    println!("                              funds, duplicated ids, unknown types or malformed amounts");
+    println!("   --seed <N>               - Seed of the random numbers. The same seed and options generate");
+    println!("                              the same file. By default, it is random and it is reported");
+    println!("   --output <path>          - Write the transactions to a file instead of the screen");
+    println!("   --expected <path>        - Write the balances expected after processing the transactions with");
+    println!("                              the default options. Format given by the extension, or csv");
+    println!();
+}
+
+/**
+ * Read the options of the generate command. They are the arguments after 'generate'
+ */
+pub fn parse_args(in_args: &[String]) -> Result<GenerateConfig, String> {
+    let mut clients       = 100;
+    let mut rows          = 1000;
+    let mut mix           = DEFAULT_MIX.to_vec();
+    let mut invalid_ratio = 0.0;
+    let mut seed          = None;
+    let mut output_file   = None;
+    let mut expected_file = None;
+
+    let mut args = in_args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clients" => {
+                clients = match args.next().map(|a| a.parse::<u16>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    Some(_)              => return Err( "ERROR: Invalid value for --clients. It shall be from 1 to 65535".to_string() ),
+                    None                 => return Err( "ERROR: Missing value for --clients".to_string() ),
+                };
+            },
+            "--rows" => {
+                rows = match args.next().map(|a| a.parse::<u64>()) {
+                    Some(Ok(n)) => n,
+                    Some(_)     => return Err( "ERROR: Invalid value for --rows. It shall be a number".to_string() ),
+                    None        => return Err( "ERROR: Missing value for --rows".to_string() ),
+                };
+            },
+            "--mix" => {
+                mix = match args.next() {
+                    Some(text) => parse_mix(text)?,
+                    None       => return Err( "ERROR: Missing value for --mix".to_string() ),
+                };
+            },
+            "--invalid" => {
+                invalid_ratio = match args.next().map(|a| a.parse::<f64>()) {
+                    Some(Ok(r)) if (0.0..=1.0).contains(&r) => r,
+                    Some(_)                                 => return Err( "ERROR: Invalid value for --invalid. It shall be from 0 to 1".to_string() ),
+                    None                                    => return Err( "ERROR: Missing value for --invalid".to_string() ),
+                };
+            },
+            "--seed" => {
+                seed = match args.next().map(|a| a.parse::<u64>()) {
+                    Some(Ok(n)) => Some(n),
+                    Some(_)     => return Err( "ERROR: Invalid value for --seed. It shall be a number".to_string() ),
+                    None        => return Err( "ERROR: Missing value for --seed".to_string() ),
+                };
+            },
+            "--output" => {
+                output_file = Some( args.next().ok_or("ERROR: Missing value for --output")?.clone() );
+            },
+            "--expected" => {
+                expected_file = Some( args.next().ok_or("ERROR: Missing value for --expected")?.clone() );
+            },
+            other => return Err( format!("ERROR: Unknown option: {}", other) ),
+        }
+    }
+
+    Ok(GenerateConfig {
+        clients,
+        rows,
+        mix,
+        invalid_ratio,
+        seed,
+        output_file,
+        expected_file,
+    })
+}
+
+/**
+ * Read a mix like "deposit=70,withdrawal=30"
+ */
+fn parse_mix(in_text: &str) -> Result<Vec<(TransactionKind, u32)>, String> {
+    let aliases = KindAliases::new();
+    let mut mix = Vec::new();
+
+    for item in in_text.split(',') {
+        let (name, weight) = item.split_once('=')
+                                 .ok_or_else(|| format!("ERROR: Invalid value for --mix: {}. It shall be <type>=<weight>", item))?;
+        let kind = aliases.resolve(name.trim())
+                          .map_err(|e| format!("ERROR: Invalid value for --mix: {}", e))?;
+        let weight = weight.trim()
+                           .parse::<u32>()
+                           .map_err(|_| format!("ERROR: Invalid weight for --mix: {}", item))?;
+        mix.push((kind, weight));
+    }
+
+    if mix.iter().all(|(_, w)| *w == 0) {
+        return Err( "ERROR: Invalid value for --mix. At least one weight shall be positive".to_string() );
+    }
+    Ok(mix)
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * SplitMix64. Simple and good enough for test data, and the same seed always gives the
+ * same numbers in every platform
+ */
+struct Random {
+    state:         u64,
+}
+
+impl Random {
+    fn new(in_seed: u64) -> Self {
+        Random { state: in_seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /**
+     * Number from 0 to in_limit - 1
+     */
+    fn below(&mut self, in_limit: u64) -> u64 {
+        self.next() % in_limit
+    }
+
+    /**
+     * True with the given probability
+     */
+    fn chance(&mut self, in_probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < in_probability
+    }
+}
+
+/**
+ * Kinds of invalid rows. All of them are rejected by the tool
+ */
+#[derive(Debug, Clone, Copy)]
+enum InvalidRow {
+    InsufficientFunds,
+    DuplicateTx,
+    UnknownTx,
+    ClientMismatch,
+    NotDisputed,
+    InvalidAmount,
+    UnknownType,
+    MalformedAmount,
+    AccountLocked,
+}
+
+const INVALID_ROWS: [InvalidRow; 9] = [
+    InvalidRow::InsufficientFunds,
+    InvalidRow::DuplicateTx,
+    InvalidRow::UnknownTx,
+    InvalidRow::ClientMismatch,
+    InvalidRow::NotDisputed,
+    InvalidRow::InvalidAmount,
+    InvalidRow::UnknownType,
+    InvalidRow::MalformedAmount,
+    InvalidRow::AccountLocked,
+];
+
+/**
+ * Row of the generated file
+ */
+struct Row {
+    kind:          String,
+    client_id:     u16,
+    tx_id:         u32,
+    amount:        String,
+}
+
+impl Row {
+    fn new(in_kind: TransactionKind, in_client_id: u16, in_tx_id: u32, in_amount: Option<Amount>) -> Self {
+        Row {
+            kind:       in_kind.name().to_string(),
+            client_id:  in_client_id,
+            tx_id:      in_tx_id,
+            amount:     in_amount.map_or(String::new(), |a| a.to_string()),
+        }
+    }
+}
+
+/**
+ * Ledger of the generated transactions, as the engine shall see them with the default
+ * options. Operations on locked accounts are rejected
+ */
+struct Ledger {
+    random:        Random,
+    clients:       u16,
+    accounts:      HashMap<u16, ClientAccount>,
+    // Client and amount of every deposit
+    deposits:      HashMap<u32, (u16, Amount)>,
+    // Deposits that can be disputed, and deposits under dispute
+    normal:        Vec<u32>,
+    disputed:      Vec<u32>,
+    // Ids of the accepted deposits and withdrawals
+    used_ids:      Vec<u32>,
+    locked:        Vec<u16>,
+    next_tx_id:    u32,
+}
+
+impl Ledger {
+    fn new(in_clients: u16, in_seed: u64) -> Self {
+        Ledger {
+            random:      Random::new(in_seed),
+            clients:     in_clients,
+            accounts:    HashMap::new(),
+            deposits:    HashMap::new(),
+            normal:      Vec::new(),
+            disputed:    Vec::new(),
+            used_ids:    Vec::new(),
+            locked:      Vec::new(),
+            next_tx_id:  1,
+        }
+    }
+
+    fn new_tx_id(&mut self) -> u32 {
+        let tx_id = self.next_tx_id;
+        self.next_tx_id += 1;
+        tx_id
+    }
+
+    fn random_client(&mut self) -> u16 {
+        self.random.below(u64::from(self.clients)) as u16 + 1
+    }
+
+    fn is_locked(&self, in_client_id: u16) -> bool {
+        self.accounts.get(&in_client_id).is_some_and(|a| a.locked)
+    }
+
+    /**
+     * A client whose account is not locked, and that matches the condition
+     */
+    fn find_client<F: Fn(&Ledger, u16) -> bool>(&mut self, in_condition: F) -> Option<u16> {
+        for _ in 0..MAX_ATTEMPTS {
+            let client_id = self.random_client();
+            if !self.is_locked(client_id) && in_condition(self, client_id) {
+                return Some(client_id);
+            }
+        }
+        None
+    }
+
+    /**
+     * Take a deposit from a list, whose client is not locked. Deposits of locked clients are
+     * dropped from the list, they can not change anymore
+     */
+    fn take_deposit(&mut self, in_disputed: bool) -> Option<(u32, u16, Amount)> {
+        for _ in 0..MAX_ATTEMPTS {
+            let list = if in_disputed { &mut self.disputed } else { &mut self.normal };
+            if list.is_empty() {
+                return None;
+            }
+            let index = self.random.below(list.len() as u64) as usize;
+            let tx_id = list.swap_remove(index);
+
+            let (client_id, amount) = self.deposits[&tx_id];
+            if !self.is_locked(client_id) {
+                return Some((tx_id, client_id, amount));
+            }
+        }
+        None
+    }
+
+    /**
+     * Amount of a deposit: from 1.00 to 5000.00, sometimes with 4 decimal digits
+     */
+    fn deposit_amount(&mut self) -> Amount {
+        let mut raw = (self.random.below(500_000) as i64 + 100) * 100;
+        if self.random.chance(0.1) {
+            raw += self.random.below(100) as i64;
+        }
+        Amount::from_raw(raw)
+    }
+
+    /**
+     * Amount of a withdrawal, up to the given funds. Rounded to cents if possible
+     */
+    fn withdrawal_amount(&mut self, in_available: Amount) -> Amount {
+        let raw = self.random.below(in_available.raw() as u64) as i64 + 1;
+        if raw >= 100 {
+            Amount::from_raw(raw - raw % 100)
+        } else {
+            Amount::from_raw(raw)
+        }
+    }
+
+    fn account(&mut self, in_client_id: u16) -> &mut ClientAccount {
+        self.accounts.entry(in_client_id).or_insert_with(|| ClientAccount::new(in_client_id))
+    }
+
+    /**
+     * A valid row of the given type. None if no client or transaction fits it
+     */
+    fn valid_row(&mut self, in_kind: TransactionKind) -> Option<Row> {
+        match in_kind {
+            TransactionKind::Deposit => {
+                let client_id = self.find_client(|_, _| true)?;
+                let amount = self.deposit_amount();
+                let tx_id = self.new_tx_id();
+
+                let account = self.account(client_id);
+                account.available = account.available.checked_add(amount)?;
+                account.total     = account.total.checked_add(amount)?;
+
+                self.deposits.insert(tx_id, (client_id, amount));
+                self.normal.push(tx_id);
+                self.used_ids.push(tx_id);
+                Some( Row::new(in_kind, client_id, tx_id, Some(amount)) )
+            },
+            TransactionKind::Withdrawal => {
+                let client_id = self.find_client(|l, c| l.accounts.get(&c).is_some_and(|a| a.available > Amount::ZERO))?;
+                let available = self.account(client_id).available;
+                let amount = self.withdrawal_amount(available);
+                let tx_id = self.new_tx_id();
+
+                let account = self.account(client_id);
+                account.available = account.available.checked_sub(amount)?;
+                account.total     = account.total.checked_sub(amount)?;
+
+                self.used_ids.push(tx_id);
+                Some( Row::new(in_kind, client_id, tx_id, Some(amount)) )
+            },
+            TransactionKind::Dispute => {
+                let (tx_id, client_id, amount) = self.take_deposit(false)?;
+
+                let account = self.account(client_id);
+                account.available = account.available.checked_sub(amount)?;
+                account.held      = account.held.checked_add(amount)?;
+
+                self.disputed.push(tx_id);
+                Some( Row::new(in_kind, client_id, tx_id, None) )
+            },
+            TransactionKind::Resolve => {
+                // A resolved deposit can not be disputed again
+                let (tx_id, client_id, amount) = self.take_deposit(true)?;
+
+                let account = self.account(client_id);
+                account.available = account.available.checked_add(amount)?;
+                account.held      = account.held.checked_sub(amount)?;
+                Some( Row::new(in_kind, client_id, tx_id, None) )
+            },
+            TransactionKind::Chargeback => {
+                let (tx_id, client_id, amount) = self.take_deposit(true)?;
+
+                let account = self.account(client_id);
+                account.held   = account.held.checked_sub(amount)?;
+                account.total  = account.total.checked_sub(amount)?;
+                account.locked = true;
+
+                self.locked.push(client_id);
+                Some( Row::new(in_kind, client_id, tx_id, None) )
+            },
+            TransactionKind::Unlock => {
+                if self.locked.is_empty() {
+                    return None;
+                }
+                let index = self.random.below(self.locked.len() as u64) as usize;
+                let client_id = self.locked.swap_remove(index);
+
+                self.account(client_id).locked = false;
+                Some( Row::new(in_kind, client_id, self.new_tx_id(), None) )
+            },
+        }
+    }
+
+    /**
+     * A row that the tool rejects. It does not change the balances. None if there is no
+     * transaction or client to build it
+     */
+    fn invalid_row(&mut self, in_invalid: InvalidRow) -> Option<Row> {
+        match in_invalid {
+            InvalidRow::InsufficientFunds => {
+                let client_id = self.find_client(|_, _| true)?;
+                let available = self.accounts.get(&client_id).map_or(Amount::ZERO, |a| a.available).max(Amount::ZERO);
+                let amount = available.checked_add(self.deposit_amount())?;
+                let tx_id = self.new_tx_id();
+                Some( Row::new(TransactionKind::Withdrawal, client_id, tx_id, Some(amount)) )
+            },
+            InvalidRow::DuplicateTx => {
+                if self.used_ids.is_empty() {
+                    return None;
+                }
+                let tx_id = self.used_ids[self.random.below(self.used_ids.len() as u64) as usize];
+                let client_id = self.find_client(|_, _| true)?;
+                let amount = self.deposit_amount();
+                Some( Row::new(TransactionKind::Deposit, client_id, tx_id, Some(amount)) )
+            },
+            InvalidRow::UnknownTx => {
+                let client_id = self.random_client();
+                let tx_id = self.new_tx_id();
+                Some( Row::new(TransactionKind::Dispute, client_id, tx_id, None) )
+            },
+            InvalidRow::ClientMismatch => {
+                let tx_id = *self.normal.get(self.random.below(self.normal.len().max(1) as u64) as usize)?;
+                let owner = self.deposits[&tx_id].0;
+                let client_id = self.find_client(|_, c| c != owner)?;
+                Some( Row::new(TransactionKind::Dispute, client_id, tx_id, None) )
+            },
+            InvalidRow::NotDisputed => {
+                let tx_id = *self.normal.get(self.random.below(self.normal.len().max(1) as u64) as usize)?;
+                let client_id = self.deposits[&tx_id].0;
+                Some( Row::new(TransactionKind::Resolve, client_id, tx_id, None) )
+            },
+            InvalidRow::InvalidAmount => {
+                let client_id = self.random_client();
+                let amount = Amount::from_raw(-(self.random.below(1_000_000) as i64));
+                let tx_id = self.new_tx_id();
+                Some( Row::new(TransactionKind::Deposit, client_id, tx_id, Some(amount)) )
+            },
+            InvalidRow::UnknownType => {
+                let client_id = self.random_client();
+                let tx_id = self.new_tx_id();
+                let mut row = Row::new(TransactionKind::Deposit, client_id, tx_id, Some(self.deposit_amount()));
+                row.kind = "transfer".to_string();
+                Some(row)
+            },
+            InvalidRow::MalformedAmount => {
+                let client_id = self.random_client();
+                let tx_id = self.new_tx_id();
+                let mut row = Row::new(TransactionKind::Deposit, client_id, tx_id, None);
+                row.amount = if self.random.chance(0.5) { "12.345678".to_string() } else { "1O0.00".to_string() };
+                Some(row)
+            },
+            InvalidRow::AccountLocked => {
+                let client_id = *self.locked.get(self.random.below(self.locked.len().max(1) as u64) as usize)?;
+                let tx_id = self.new_tx_id();
+                Some( Row::new(TransactionKind::Deposit, client_id, tx_id, Some(self.deposit_amount())) )
+            },
+        }
+    }
+
+    /**
+     * Next row of the file, and whether it is invalid. Invalid rows are generated with the
+     * given probability. None if no valid row fits, i.e. all the accounts are locked
+     */
+    fn next_row(&mut self, in_mix: &[(TransactionKind, u32)], in_invalid_ratio: f64) -> Option<(Row, bool)> {
+        if self.random.chance(in_invalid_ratio) {
+            loop {
+                let invalid = INVALID_ROWS[self.random.below(INVALID_ROWS.len() as u64) as usize];
+                if let Some(row) = self.invalid_row(invalid) {
+                    return Some((row, true));
+                }
+            }
+        }
+
+        let total_weight: u64 = in_mix.iter().map(|(_, w)| u64::from(*w)).sum();
+        for _ in 0..MAX_ATTEMPTS {
+            let mut pick = self.random.below(total_weight);
+            let kind = in_mix.iter()
+                             .find(|(_, w)| {
+                                 let found = pick < u64::from(*w);
+                                 pick = pick.saturating_sub(u64::from(*w));
+                                 found
+                             })
+                             .map_or(TransactionKind::Deposit, |(k, _)| *k);
+
+            if let Some(row) = self.valid_row(kind) {
+                return Some((row, false));
+            }
+        }
+
+        // Nothing of the mix fits by chance, i.e. disputes without deposits. Every type of the
+        // mix is tried, then a deposit, that always fits unless all the accounts are locked
+        let kind_list = in_mix.iter()
+                              .filter(|(_, w)| *w > 0)
+                              .map(|(k, _)| *k)
+                              .chain(Some(TransactionKind::Deposit));
+        for kind in kind_list {
+            if let Some(row) = self.valid_row(kind) {
+                return Some((row, false));
+            }
+        }
+        None
+    }
+
+    /**
+     * Whether every client has a locked account, so only unlocks are valid
+     */
+    fn all_locked(&self) -> bool {
+        (1..=self.clients).all(|c| self.is_locked(c))
+    }
+}
+
+/**
+ * Write the generated transactions. It returns the number of invalid rows
+ */
+fn write_rows<W: Write>(in_writer: W, in_config: &GenerateConfig, in_ledger: &mut Ledger) -> Result<(W, u64), String> {
+    let mut csv_writer = csv::Writer::from_writer(in_writer);
+    let mut invalid_count = 0;
+
+    csv_writer.write_record(["type", "client", "tx", "amount"])
+              .map_err(|e| format!("ERROR: Writing transactions: {}", e))?;
+
+    for row_number in 1..=in_config.rows {
+        let (row, invalid) = in_ledger.next_row(&in_config.mix, in_config.invalid_ratio)
+                                      .ok_or_else(|| no_valid_row_error(in_ledger, row_number))?;
+        if invalid {
+            invalid_count += 1;
+        }
+
+        csv_writer.write_record([row.kind, row.client_id.to_string(), row.tx_id.to_string(), row.amount])
+                  .map_err(|e| format!("ERROR: Writing transactions: {}", e))?;
+    }
+
+    let writer = csv_writer.into_inner()
+                           .map_err(|e| format!("ERROR: Writing transactions: {}", e.error()))?;
+    Ok((writer, invalid_count))
+}
+
+/**
+ * Error when no valid row fits the options. Rows are not made invalid beyond the
+ * requested ratio, so the file can not be generated
+ */
+fn no_valid_row_error(in_ledger: &Ledger, in_row_number: u64) -> String {
+    if in_ledger.all_locked() {
+        format!("ERROR: No valid row fits at row: {}. All the accounts are locked by chargebacks and \
+                 --mix has no unlocks. Add unlocks or more clients", in_row_number)
+    } else {
+        format!("ERROR: No valid row fits at row: {}", in_row_number)
+    }
+}
+
+/**
+ * Generate the transactions file and, if requested, the expected balances
+ */
+pub fn run(in_config: &GenerateConfig) -> Result<(), String> {
+    let seed = in_config.seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+    });
+    let mut ledger = Ledger::new(in_config.clients, seed);
+
+    let invalid_count = match &in_config.output_file {
+        Some(path) => {
+            let mut invalid_count = 0;
+            write_file_atomically(path, |w| {
+                let (w, n) = write_rows(w, in_config, &mut ledger)?;
+                invalid_count = n;
+                Ok(w)
+            })?;
+            invalid_count
+        },
+        None => {
+            let stdout = io::stdout();
+            let (mut w, invalid_count) = write_rows(stdout.lock(), in_config, &mut ledger)?;
+            w.flush().map_err(|e| format!("ERROR: Writing transactions: {}", e))?;
+            invalid_count
+        },
+    };
+
+    if let Some(path) = &in_config.expected_file {
+        let accounts = ledger.accounts.into_values().collect();
+        let engine = PaymentEngine::from_parts(LockedPolicy::Reject, accounts, Box::new(MemoryStore::new()));
+        let format = DataFormat::from_path(Path::new(path)).unwrap_or(DataFormat::Csv);
+
+        write_accounts_file(path, &engine, AccountOrder::default(), format)?;
+    }
+
+    eprintln!("Generated {} rows, {} of them invalid, with seed: {}", in_config.rows, invalid_count, seed);
+    Ok(())
+}
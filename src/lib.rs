@@ -0,0 +1,27 @@
+/*
+ *  Batch payment system. Library with the payment engine, so it can be embedded
+ *  in other services. The command line tool is a thin wrapper around it
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+pub mod amount;
+mod encoding;
+mod engine;
+mod error;
+mod parallel;
+mod record;
+mod state;
+mod store;
+mod transaction;
+
+pub use amount::Amount;
+pub use encoding::{DecodingReader, Encoding};
+pub use engine::{LockedPolicy, PaymentEngine, ReplayedTransaction, SourceRow};
+pub use error::EngineError;
+pub use parallel::{ParallelEngine, ParallelResult, ParallelRow};
+pub use record::{json_headers, read_json_transaction, read_transaction, write_csv_accounts, write_json_accounts, write_ndjson_accounts, Rejection, TRANSACTION_COLUMNS};
+pub use store::{DiskStore, MemoryStore, RecordingStore, SharedStore, StoreChanges, StoreState, StoredDeposit, StoredTransaction, TransactionState, TransactionStore, TxIdSet};
+pub use transaction::{ClientAccount, KindAliases, QueuedTransaction, Transaction, TransactionKind, UnknownKind};
@@ -1,5 +1,6 @@
 /*
- *  Batch CSV payment system. It processes a CSV file and generates the balance per client
+ *  Batch CSV payment system. It processes a CSV file and generates the balance per client.
+ *  Command line wrapper around the payment engine
  * 
  *  Author:    Alberto Fernandez
  *  Date:      13/02/2021
@@ -7,302 +8,1494 @@
  */
 
 
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::fs::File;
-use std::io;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::process;
-use std::collections::HashMap;
-use std::path::Path;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
+use csv::{ByteRecord, StringRecord, Trim};
 use serde::{Deserialize, Serialize};
-use csv::{Trim};
+use serde_json::value::RawValue;
 
+use csv_payment::{ClientAccount, DecodingReader, DiskStore, Encoding, EngineError, KindAliases, LockedPolicy, MemoryStore, ParallelEngine, ParallelResult, ParallelRow, PaymentEngine, QueuedTransaction, RecordingStore, Rejection, ReplayedTransaction, SourceRow, StoreChanges, StoreState, Transaction, TransactionKind, TransactionStore};
+use csv_payment::{json_headers, read_json_transaction, read_transaction, write_csv_accounts, write_json_accounts, write_ndjson_accounts, TRANSACTION_COLUMNS};
 
-#[derive(Serialize)]
+mod generate;
 
-#[derive(Debug, Clone, Deserialize)]
-struct Transaction {
-    // Types can be; deposit, withdrawal, dispute, resolve, chargeback
-    #[serde(rename = "type")]
-    type_name:     String,
-    #[serde(rename = "client")]
-    client_id:     u16,
-    #[serde(rename = "tx")]
-    tx_id:         u32,
-    amount:        f32,
+
+/// Version of the format of the checkpoint file
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// Rows between checkpoints, by default
+const CHECKPOINT_EVERY: u64 = 100_000;
+
+
+/**
+ * What to do when a transaction can not be read or processed
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorPolicy {
+    // Report it and continue with the next transaction
+    Skip,
+    // Stop processing at the first error
+    Abort,
+    // Continue until the given number of errors is reached
+    StopAfter(usize),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ClientAccount {
-    #[serde(rename = "client")]
-    client_id:     u16,
-    available:     f32,
-    held:          f32,
-    total:         f32,
-    locked:        bool,
+impl ErrorPolicy {
+    /**
+     * Check if processing shall stop after the given number of errors
+     */
+    fn must_stop(self, in_error_count: usize) -> bool {
+        match self {
+            ErrorPolicy::Skip         => false,
+            ErrorPolicy::Abort        => in_error_count > 0,
+            ErrorPolicy::StopAfter(n) => in_error_count >= n,
+        }
+    }
 }
 
-impl ClientAccount {
-    pub fn new(in_client_id: u16) -> Self {
-        ClientAccount {
-            client_id:  in_client_id,
-            available:  0.0,
-            held:       0.0,
-            total:      0.0,
-            locked:     false,
+/**
+ * Format of the input and output files
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataFormat {
+    Csv,
+    // JSON array of objects
+    Json,
+    // One JSON object per line
+    Ndjson,
+}
+
+impl DataFormat {
+    fn parse(in_text: &str) -> Result<Self, String> {
+        match in_text {
+            "csv"    => Ok(DataFormat::Csv),
+            "json"   => Ok(DataFormat::Json),
+            "ndjson" => Ok(DataFormat::Ndjson),
+            _        => Err( format!("ERROR: Invalid format: {}", in_text) ),
+        }
+    }
+
+    /**
+     * Format given by the extension of a file: .csv, .json, .ndjson or .jsonl
+     */
+    fn from_path(in_path: &Path) -> Option<Self> {
+        let extension = in_path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "csv"             => Some(DataFormat::Csv),
+            "json"            => Some(DataFormat::Json),
+            "ndjson" | "jsonl" => Some(DataFormat::Ndjson),
+            _                 => None,
         }
     }
 }
 
+/**
+ * How CSV inputs are written: separators, header and column names
+ */
+#[derive(Debug, Clone)]
+struct CsvDialect {
+    delimiter:     u8,
+    quote:         u8,
+    // Lines starting with this character are ignored
+    comment:       Option<u8>,
+    // If false, the first row is a transaction and the column names are the given ones
+    has_header:    bool,
+    columns:       Vec<String>,
+    // Column names of the input mapped to the standard ones, i.e. txid -> tx
+    renames:       HashMap<String, String>,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        CsvDialect {
+            delimiter:  b',',
+            quote:      b'"',
+            comment:    None,
+            has_header: true,
+            columns:    TRANSACTION_COLUMNS.iter().map(|c| c.to_string()).collect(),
+            renames:    HashMap::new(),
+        }
+    }
+}
+
+impl CsvDialect {
+    /**
+     * Read a separator given as a single ASCII character. "tab" or "\t" is accepted for tabs
+     */
+    fn parse_char(in_option: &str, in_text: &str) -> Result<u8, String> {
+        match in_text {
+            "tab" | "\\t" | "\t" => Ok(b'\t'),
+            _ if in_text.len() == 1 && in_text.is_ascii() => Ok(in_text.as_bytes()[0]),
+            _ => Err( format!("ERROR: Invalid value for {}: {}. It shall be a single character", in_option, in_text) ),
+        }
+    }
+
+    /**
+     * Standard names of the input columns: the header row, or the given columns if there is 
+     * no header, after applying the renames
+     */
+    fn map_headers(&self, in_headers: &StringRecord) -> StringRecord {
+        in_headers.iter()
+                  .map(|h| self.renames.get(h).map_or(h, |n| n.as_str()))
+                  .collect()
+    }
+}
+
+/**
+ * Field used to sort the accounts in the output
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Client,
+    Available,
+    Held,
+    Total,
+}
+
+/**
+ * Order of the accounts in the output. Accounts with the same value are
+ * ordered by client id, so the output is always the same for the same input
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AccountOrder {
+    key:            SortKey,
+    descending:     bool,
+}
+
+impl AccountOrder {
+    /**
+     * Read an order like "total" or "total:desc"
+     */
+    fn parse(in_text: &str) -> Result<Self, String> {
+        let (key_text, direction) = match in_text.split_once(':') {
+            Some((k, d)) => (k, d),
+            None         => (in_text, "asc"),
+        };
+
+        let key = match key_text {
+            "client"    => SortKey::Client,
+            "available" => SortKey::Available,
+            "held"      => SortKey::Held,
+            "total"     => SortKey::Total,
+            _           => return Err( format!("ERROR: Invalid sort field: {}", key_text) ),
+        };
+        let descending = match direction {
+            "asc"  => false,
+            "desc" => true,
+            _      => return Err( format!("ERROR: Invalid sort direction: {}", direction) ),
+        };
+
+        Ok(AccountOrder { key, descending })
+    }
+
+    fn sort(self, in_accounts: &mut [ClientAccount]) {
+        in_accounts.sort_by(|a, b| {
+            let ordering = match self.key {
+                SortKey::Client    => a.client_id.cmp(&b.client_id),
+                SortKey::Available => a.available.cmp(&b.available),
+                SortKey::Held      => a.held.cmp(&b.held),
+                SortKey::Total     => a.total.cmp(&b.total),
+            };
+            let ordering = if self.descending { ordering.reverse() } else { ordering };
+
+            ordering.then(a.client_id.cmp(&b.client_id))
+        });
+    }
+}
+
+impl Default for AccountOrder {
+    fn default() -> Self {
+        AccountOrder {
+            key:         SortKey::Client,
+            descending:  false,
+        }
+    }
+}
+
+/**
+ * Command line options
+ */
+#[derive(Debug, Clone)]
+struct Config {
+    input_files:    Vec<String>,
+    locked_policy:  LockedPolicy,
+    error_policy:   ErrorPolicy,
+    rejects_file:   Option<String>,
+    aliases:        KindAliases,
+    order:          AccountOrder,
+    output_file:    Option<String>,
+    input_format:   Option<DataFormat>,
+    output_format:  Option<DataFormat>,
+    dialect:        CsvDialect,
+    // None to detect it from the byte order mark
+    encoding:       Option<Encoding>,
+    deposit_limit:  Option<usize>,
+    store_file:     Option<String>,
+    state_file:     Option<String>,
+    checkpoint_file:  Option<String>,
+    checkpoint_every: u64,
+    resume:         bool,
+    threads:        usize,
+}
+
 // ---------------------------------------------------------------------
 
 fn usage() {
     println!("Batch CSV Payment");
-    println!("Usage:     csv_payment   input_transactions.csv");
-    println!("");
-    println!("   input_transactions.csv - CSV file containing the list of transactions");
-    println!("                            Columns: type (string), client id (unsigned), transaction id(unsigned), amount (float)");
-    println!("");
+    println!("Usage:     csv_payment   [options]   input_transactions.csv...");
+    println!("           csv_payment   generate   [options]   - Generate a transactions file. See 'generate --help'");
+    println!();
+    println!("   input_transactions.csv - CSV files containing the list of transactions. They are processed in");
+    println!("                            order, as one continuous ledger. '-' reads the standard input and");
+    println!("                            patterns like 'day_*.csv' are expanded in alphabetical order");
+    println!("                            Columns: type (string), client id (unsigned), transaction id(unsigned), amount (decimal, up to 4 digits)");
+    println!("                            The amount is only required for deposit and withdrawal");
+    println!();
+    println!("Options:");
+    println!("   --locked <reject|queue>  - Operations on a locked account are rejected (default) or queued until");
+    println!("                              the account is unlocked with an 'unlock' transaction. Without --state,");
+    println!("                              the operations still queued at the end are rejected");
+    println!("   --on-error <skip|abort>  - Invalid transactions are reported and skipped (default), or processing");
+    println!("                              is aborted at the first one");
+    println!("   --max-errors <N>         - Abort processing when N invalid transactions are found");
+    println!("   --rejects <path>         - Write the rejected rows to a CSV file, with their line number and");
+    println!("                              reason code. Errors are always reported on the standard error");
+    println!("   --alias <name>=<type>    - Accept another name for a transaction type, i.e. credit=deposit.");
+    println!("                              It can be repeated. Type names are case insensitive");
+    println!("   --sort <field>[:asc|desc] - Order of the accounts in the output. Field: client (default),");
+    println!("                              available, held or total");
+    println!("   --output <path>          - Write the balances to a file instead of the screen. The file is only");
+    println!("                              replaced when all the balances have been written");
+    println!("   --input-format <format>  - Format of the inputs: csv, json (array of objects) or ndjson (one object");
+    println!("                              per line). By default, it is given by the file extension, or csv");
+    println!("   --output-format <format> - Format of the balances: csv, json or ndjson. By default, it is given");
+    println!("                              by the extension of the output file, or csv");
+    println!("   --deposit-limit <N>      - Keep at most N deposits for later disputes, so memory is bounded. Older");
+    println!("                              deposits can not be disputed. By default, all of them are kept");
+    println!("   --store <path>           - Keep the transactions in a SQLite file instead of memory. The file is");
+    println!("                              created if it does not exist, and it keeps its contents between runs.");
+    println!("                              It requires --state. The changes of a run that is aborted are undone");
+    println!("                              by the next one");
+    println!("   --state <path>           - Load the accounts and transactions of previous runs from this file, if");
+    println!("                              it exists, and save them at the end. So runs continue the same ledger.");
+    println!("                              With --store, the transactions are kept in the store file, and both");
+    println!("                              files shall be from the same run");
+    println!("   --checkpoint <path>      - Save the progress and the state of the engine in this file every");
+    println!("                              --checkpoint-every rows (default {}). The transactions are added to", CHECKPOINT_EVERY);
+    println!("                              '<path>.log'. Both files are removed when the run ends. It can not");
+    println!("                              be used with --store");
+    println!("   --checkpoint-every <N>   - Rows between checkpoints");
+    println!("   --resume                 - Continue an interrupted run after its last checkpoint. The inputs and");
+    println!("                              the options shall be the same");
+    println!("   --threads <N>            - Apply the transactions in N threads, splitting the clients between");
+    println!("                              them. Balances are the same as with one thread (default), but rejected");
+    println!("                              rows may be reported in a different order. Not valid with --checkpoint");
+    println!("   --encoding <name>        - Character encoding of the inputs: auto (default), utf-8, utf-16le,");
+    println!("                              utf-16be, windows-1252 or latin-1. 'auto' detects UTF-16 and removes");
+    println!("                              the byte order mark. Rows that can not be decoded are rejected");
+    println!();
+    println!("CSV input options:");
+    println!("   --delimiter <char>       - Field separator. Comma by default. 'tab' for tabs");
+    println!("   --quote <char>           - Quote character. Double quote by default");
+    println!("   --comment <char>         - Lines starting with this character are ignored");
+    println!("   --no-header              - The inputs have no header row. Columns are type,client,tx,amount");
+    println!("   --columns <names>        - Comma separated column names of inputs without header row. It implies");
+    println!("                              --no-header");
+    println!("   --rename <column>=<name> - Read a column as one of the standard ones: type, client, tx or amount,");
+    println!("                              i.e. txid=tx. It can be repeated");
+    println!();
+    println!("Exit code:   0 - All transactions processed");
+    println!("             1 - Some transactions were rejected. Balances are written");
+    println!("            -1 - Processing aborted or invalid parameters. Balances are not written");
+    println!();
 }
 
 /**
- * Search a client. If it does not exist, it will add it to the list and return it
+ * Read the command line options
  */
-fn get_add_client(in_id: u16, in_client_list: &mut HashMap<u16, ClientAccount>) -> Result<ClientAccount, String> {
-    if in_client_list.contains_key(&in_id) == false {
-        // Client does not exist. Create it
-        let new_client = ClientAccount::new(in_id);
-        in_client_list.insert( in_id, new_client.clone() );
+fn parse_args(in_args: &[String]) -> Result<Config, String> {
+    let mut input_files   = Vec::new();
+    let mut locked_policy = LockedPolicy::Reject;
+    let mut error_policy  = ErrorPolicy::Skip;
+    let mut rejects_file  = None;
+    let mut aliases       = KindAliases::new();
+    let mut order         = AccountOrder::default();
+    let mut output_file   = None;
+    let mut input_format  = None;
+    let mut output_format = None;
+    let mut dialect       = CsvDialect::default();
+    let mut encoding      = None;
+    let mut deposit_limit = None;
+    let mut store_file    = None;
+    let mut state_file    = None;
+    let mut checkpoint_file  = None;
+    let mut checkpoint_every = CHECKPOINT_EVERY;
+    let mut resume        = false;
+    let mut threads       = 1;
 
-        Ok(new_client)
-    } else {
-        match in_client_list.get_mut(&in_id) {
-            Some(c) => Ok( c.clone() ),
-            None =>    Err( format!("Unable to find client: {} ", in_id) ),
+    let mut args = in_args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--locked" => {
+                locked_policy = match args.next().map(|a| a.as_str()) {
+                    Some("reject") => LockedPolicy::Reject,
+                    Some("queue")  => LockedPolicy::Queue,
+                    Some(other)    => return Err( format!("ERROR: Invalid value for --locked: {}", other) ),
+                    None           => return Err( "ERROR: Missing value for --locked".to_string() ),
+                };
+            },
+            "--on-error" => {
+                error_policy = match args.next().map(|a| a.as_str()) {
+                    Some("skip")  => ErrorPolicy::Skip,
+                    Some("abort") => ErrorPolicy::Abort,
+                    Some(other)   => return Err( format!("ERROR: Invalid value for --on-error: {}", other) ),
+                    None          => return Err( "ERROR: Missing value for --on-error".to_string() ),
+                };
+            },
+            "--max-errors" => {
+                error_policy = match args.next().map(|a| a.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => ErrorPolicy::StopAfter(n),
+                    Some(_)              => return Err( "ERROR: Invalid value for --max-errors. It shall be a positive number".to_string() ),
+                    None                 => return Err( "ERROR: Missing value for --max-errors".to_string() ),
+                };
+            },
+            "--rejects" => {
+                match args.next() {
+                    Some(path) => rejects_file = Some(path.clone()),
+                    None       => return Err( "ERROR: Missing value for --rejects".to_string() ),
+                }
+            },
+            "--alias" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --alias".to_string())?;
+                match value.split_once('=') {
+                    Some((alias, kind)) if !alias.trim().is_empty() => {
+                        let kind = kind.parse::<TransactionKind>()
+                                       .map_err(|e| format!("ERROR: Invalid value for --alias: {}", e))?;
+                        aliases.add(alias, kind);
+                    },
+                    _ => return Err( format!("ERROR: Invalid value for --alias: {}. Expected <name>=<type>", value) ),
+                }
+            },
+            "--sort" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --sort".to_string())?;
+                order = AccountOrder::parse(value)?;
+            },
+            "--output" => {
+                match args.next() {
+                    Some(path) => output_file = Some(path.clone()),
+                    None       => return Err( "ERROR: Missing value for --output".to_string() ),
+                }
+            },
+            "--input-format" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --input-format".to_string())?;
+                input_format = Some(DataFormat::parse(value)?);
+            },
+            "--output-format" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --output-format".to_string())?;
+                output_format = Some(DataFormat::parse(value)?);
+            },
+            "--delimiter" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --delimiter".to_string())?;
+                dialect.delimiter = CsvDialect::parse_char("--delimiter", value)?;
+            },
+            "--quote" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --quote".to_string())?;
+                dialect.quote = CsvDialect::parse_char("--quote", value)?;
+            },
+            "--comment" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --comment".to_string())?;
+                dialect.comment = Some(CsvDialect::parse_char("--comment", value)?);
+            },
+            "--no-header" => {
+                dialect.has_header = false;
+            },
+            "--columns" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --columns".to_string())?;
+                dialect.columns = value.split(',').map(|c| c.trim().to_string()).collect();
+                dialect.has_header = false;
+            },
+            "--rename" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --rename".to_string())?;
+                match value.split_once('=') {
+                    Some((from, to)) if TRANSACTION_COLUMNS.contains(&to.trim()) => {
+                        dialect.renames.insert(from.trim().to_string(), to.trim().to_string());
+                    },
+                    _ => return Err( format!("ERROR: Invalid value for --rename: {}. Expected <column>=<type|client|tx|amount>", value) ),
+                }
+            },
+            "--deposit-limit" => {
+                deposit_limit = match args.next().map(|a| a.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => Some(n),
+                    Some(_)              => return Err( "ERROR: Invalid value for --deposit-limit. It shall be a positive number".to_string() ),
+                    None                 => return Err( "ERROR: Missing value for --deposit-limit".to_string() ),
+                };
+            },
+            "--store" => {
+                match args.next() {
+                    Some(path) => store_file = Some(path.clone()),
+                    None       => return Err( "ERROR: Missing value for --store".to_string() ),
+                }
+            },
+            "--state" => {
+                match args.next() {
+                    Some(path) => state_file = Some(path.clone()),
+                    None       => return Err( "ERROR: Missing value for --state".to_string() ),
+                }
+            },
+            "--checkpoint" => {
+                match args.next() {
+                    Some(path) => checkpoint_file = Some(path.clone()),
+                    None       => return Err( "ERROR: Missing value for --checkpoint".to_string() ),
+                }
+            },
+            "--checkpoint-every" => {
+                checkpoint_every = match args.next().map(|a| a.parse::<u64>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    Some(_)              => return Err( "ERROR: Invalid value for --checkpoint-every. It shall be a positive number".to_string() ),
+                    None                 => return Err( "ERROR: Missing value for --checkpoint-every".to_string() ),
+                };
+            },
+            "--resume" => {
+                resume = true;
+            },
+            "--threads" => {
+                threads = match args.next().map(|a| a.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    Some(_)              => return Err( "ERROR: Invalid value for --threads. It shall be a positive number".to_string() ),
+                    None                 => return Err( "ERROR: Missing value for --threads".to_string() ),
+                };
+            },
+            "--encoding" => {
+                let value = args.next().ok_or_else(|| "ERROR: Missing value for --encoding".to_string())?;
+                encoding = match value.as_str() {
+                    "auto" => None,
+                    _      => Some( value.parse::<Encoding>().map_err(|e| format!("ERROR: Invalid value for --encoding: {}", e))? ),
+                };
+            },
+            _ if arg.starts_with("--") && arg != "-" => {
+                return Err( format!("ERROR: Unknown option: {}", arg) );
+            },
+            _ => {
+                input_files.push(arg.clone());
+            },
         }
     }
+
+    if input_files.is_empty() {
+        return Err( "ERROR: Missing input file".to_string() );
+    }
+    // The accounts that match the store file are kept in the state file
+    if store_file.is_some() && state_file.is_none() {
+        return Err( "ERROR: --store requires --state".to_string() );
+    }
+    if deposit_limit.is_some() && store_file.is_some() {
+        return Err( "ERROR: --deposit-limit can not be used with --store".to_string() );
+    }
+    // The store file can not go back to the checkpoint
+    if checkpoint_file.is_some() && store_file.is_some() {
+        return Err( "ERROR: --checkpoint can not be used with --store".to_string() );
+    }
+    if resume && checkpoint_file.is_none() {
+        return Err( "ERROR: --resume requires --checkpoint".to_string() );
+    }
+    if threads > 1 && checkpoint_file.is_some() {
+        return Err( "ERROR: --threads can not be used with --checkpoint".to_string() );
+    }
+
+    Ok(Config {
+        input_files,
+        locked_policy,
+        error_policy,
+        rejects_file,
+        aliases,
+        order,
+        output_file,
+        input_format,
+        output_format,
+        dialect,
+        encoding,
+        deposit_limit,
+        store_file,
+        state_file,
+        checkpoint_file,
+        checkpoint_every,
+        resume,
+        threads,
+    })
 }
 
 /**
- * Add the transaction to the list. Check if it does not exist
- */ 
-fn add_transaction(in_current_tx: &Transaction, in_transaction_list: &mut HashMap<u32, Transaction>) -> Result<i32, String> {
-    if in_transaction_list.contains_key(&in_current_tx.tx_id) == true {
-       return Err( format!("ERROR: Transactin already exist: {} ", in_current_tx.tx_id) );
+ * Rejects file. Rows keep the columns of the first input, so they can be fixed and 
+ * processed again, followed by the input name, line number, reason code and message
+ */
+struct RejectsWriter {
+    writer:        csv::Writer<File>,
+    columns:       Option<ByteRecord>,
+}
+
+impl RejectsWriter {
+    fn create(in_path: &str) -> Result<Self, String> {
+        let writer = csv::Writer::from_path(in_path)
+                                 .map_err(|e| format!("ERROR: Creating rejects file: {} {}", in_path, e))?;
+
+        Ok(RejectsWriter {
+            writer,
+            columns:  None,
+        })
+    }
+
+    /**
+     * Open the rejects file of an interrupted run. The rows written after the checkpoint
+     * are removed
+     */
+    fn reopen(in_path: &str, in_size: u64, in_columns: Option<Vec<String>>) -> Result<Self, String> {
+        let mut file = fs::OpenOptions::new().write(true).open(in_path)
+                                       .map_err(|e| format!("ERROR: Opening rejects file: {} {}", in_path, e))?;
+        file.set_len(in_size)
+            .and_then(|_| file.seek(SeekFrom::End(0)))
+            .map_err(|e| format!("ERROR: Opening rejects file: {} {}", in_path, e))?;
+
+        Ok(RejectsWriter {
+            writer:   csv::Writer::from_writer(file),
+            columns:  in_columns.map(ByteRecord::from),
+        })
+    }
+
+    /**
+     * Size of the file and its columns, after writing all the pending rows
+     */
+    fn position(&mut self) -> Result<(u64, Option<Vec<String>>), String> {
+        self.flush()?;
+        let mut file: &File = self.writer.get_ref();
+        let size = file.stream_position()
+                       .map_err(|e| format!("ERROR: Writing rejects file: {}", e))?;
+        let columns = self.columns.as_ref()
+                                  .map(|c| c.iter().map(|f| String::from_utf8_lossy(f).into_owned()).collect());
+        Ok((size, columns))
+    }
+
+    /**
+     * Take the columns of the first input and write the header
+     */
+    fn set_columns(&mut self, in_headers: &ByteRecord) -> Result<(), String> {
+        if self.columns.is_some() {
+            return Ok(());
+        }
+
+        let mut header = in_headers.clone();
+        header.push_field(b"file");
+        header.push_field(b"line");
+        header.push_field(b"reason");
+        header.push_field(b"message");
+        self.writer.write_byte_record(&header)
+                   .map_err(|e| format!("ERROR: Writing rejects file: {}", e))?;
+
+        self.columns = Some(in_headers.clone());
+        Ok(())
+    }
+
+    /**
+     * Write a rejected row. Its fields are matched to the columns by name, missing 
+     * columns are left empty and extra columns are dropped
+     */
+    fn write(&mut self, in_headers: &ByteRecord, in_record: &ByteRecord, in_source: &str, in_line: u64, in_rejection: &Rejection) -> Result<(), String> {
+        self.set_columns(in_headers)?;
+
+        let mut row = ByteRecord::new();
+        if let Some(columns) = &self.columns {
+            for column in columns {
+                let field = in_headers.iter()
+                                      .position(|h| h == column)
+                                      .and_then(|i| in_record.get(i));
+                row.push_field(field.unwrap_or(b""));
+            }
+        }
+        row.push_field(in_source.as_bytes());
+        row.push_field(in_line.to_string().as_bytes());
+        row.push_field(in_rejection.code.as_bytes());
+        row.push_field(in_rejection.message.as_bytes());
+
+        self.writer.write_byte_record(&row)
+                   .map_err(|e| format!("ERROR: Writing rejects file: {}", e))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.writer.flush()
+                   .map_err(|e| format!("ERROR: Writing rejects file: {}", e))
     }
-    
-    in_transaction_list.insert(in_current_tx.tx_id, in_current_tx.clone());
-    Ok(0)
 }
 
 /**
- * Process a transaction and update clientś account
- * 
+ * Source of transactions: a file or the standard input
  */
-fn process_transaction(in_current_tx: &Transaction, in_client_list: &mut HashMap<u16, ClientAccount>, in_transaction_list: &mut HashMap<u32, Transaction>) -> Result<i32, String> {
-
-    match in_current_tx.type_name.as_str() {
-        // -------------------------------------
-        "deposit" => {
-            // Search for client
-            let mut the_client : ClientAccount;
-            match get_add_client(in_current_tx.client_id, in_client_list) {
-                Ok(c)  => the_client = c,
-                Err(e) => { return Err(e); },
-            };
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum InputSource {
+    Stdin,
+    File(PathBuf),
+}
 
-            // Increase available and total funds of client
-            the_client.available += in_current_tx.amount;
-            the_client.total     += in_current_tx.amount;
+impl InputSource {
+    /**
+     * Name used in the error messages and in the rejects file
+     */
+    fn name(&self) -> String {
+        match self {
+            InputSource::Stdin    => "<stdin>".to_string(),
+            InputSource::File(p)  => p.display().to_string(),
+        }
+    }
 
-            // Update the client
-            if let Some(c) = in_client_list.get_mut(&in_current_tx.client_id) {
-                *c = the_client;
-            }
+    /**
+     * Format of the input: the one given in the command line, or the one of the file 
+     * extension. CSV by default
+     */
+    fn format(&self, in_config: &Config) -> DataFormat {
+        let from_extension = match self {
+            InputSource::Stdin    => None,
+            InputSource::File(p)  => DataFormat::from_path(p),
+        };
 
-            // Add the Transaction
-            if let Err(e) = add_transaction(in_current_tx, in_transaction_list) {
-                return Err(e);
-            }
-        },
+        in_config.input_format
+                 .or(from_extension)
+                 .unwrap_or(DataFormat::Csv)
+    }
 
-        // -------------------------------------
-        "withdrawal" => {
-            // Search for client
-            let mut the_client : ClientAccount;
-            match get_add_client(in_current_tx.client_id, in_client_list) {
-                Ok(c)  => the_client = c,
-                Err(e) => { return Err(e); },
-            };
+    /**
+     * Open the input. It is transcoded to UTF-8 from the given encoding, or the detected one
+     */
+    fn open(&self, in_encoding: Option<Encoding>) -> Result<Box<dyn Read>, String> {
+        match self {
+            InputSource::Stdin    => Ok( Box::new(DecodingReader::new(io::stdin(), in_encoding)) ),
+            InputSource::File(p)  => {
+                let f = File::open(p).map_err(|e| format!("ERROR: Opening input file: {} {}", p.display(), e))?;
+                Ok( Box::new(DecodingReader::new(f, in_encoding)) )
+            },
+        }
+    }
+
+    /**
+     * Open the input at a position of the decoded text, where an interrupted run stopped
+     */
+    fn open_at(&self, in_encoding: Option<Encoding>, in_offset: u64) -> Result<Box<dyn Read>, String> {
+        match self {
+            InputSource::Stdin    => Err( "ERROR: The standard input can not be resumed".to_string() ),
+            InputSource::File(p)  => {
+                let f = File::open(p).map_err(|e| format!("ERROR: Opening input file: {} {}", p.display(), e))?;
+                let mut reader = DecodingReader::new(f, in_encoding);
+                reader.seek_decoded(in_offset)
+                      .map_err(|e| format!("ERROR: Resuming input file: {} {}", p.display(), e))?;
+                Ok( Box::new(reader) )
+            },
+        }
+    }
+}
+
+/**
+ * Build the list of inputs from the command line. "-" is the standard input. Patterns 
+ * with wildcards are expanded, in alphabetical order. All the files shall exist
+ */
+fn expand_inputs(in_inputs: &[String]) -> Result<Vec<InputSource>, String> {
+    let mut sources = Vec::new();
+
+    for input in in_inputs {
+        if input == "-" {
+            if sources.contains(&InputSource::Stdin) {
+                return Err( "ERROR: The standard input can only be read once".to_string() );
+            }
+            sources.push(InputSource::Stdin);
+        } else if input.contains(['*', '?', '[']) {
+            let paths = glob::glob(input).map_err(|e| format!("ERROR: Invalid input pattern: {} {}", input, e))?;
 
-            if the_client.available > in_current_tx.amount {
-                // Decrease available and total funds of client
-                the_client.available -= in_current_tx.amount;
-                the_client.total     -= in_current_tx.amount;
-    
-                // Update the client
-                if let Some(c) = in_client_list.get_mut(&in_current_tx.client_id) {
-                    *c = the_client;
+            let mut found = false;
+            for path in paths {
+                let path = path.map_err(|e| format!("ERROR: Reading input pattern: {} {}", input, e))?;
+                if path.is_file() {
+                    sources.push(InputSource::File(path));
+                    found = true;
                 }
-            } else {
-                return Err( format!("ERROR: Client: {} has insufficient funds: {}", in_current_tx.client_id, the_client.available) );
             }
+            if !found {
+                return Err( format!("ERROR: No input file matches: {}", input) );
+            }
+        } else {
+            let path = PathBuf::from(input);
+            if !path.exists() {
+                return Err( format!("ERROR: CSV file does not exist: {}", input) );
+            }
+            sources.push(InputSource::File(path));
+        }
+    }
+
+    Ok(sources)
+}
+
+/**
+ * Identity of an input file, to check that it has not changed when a run is resumed
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct InputIdentity {
+    name:          String,
+    size:          u64,
+    // Modification time, in seconds since the epoch
+    modified:      Option<u64>,
+}
+
+impl InputIdentity {
+    fn of(in_source: &InputSource) -> Result<Self, String> {
+        match in_source {
+            InputSource::Stdin    => Err( "ERROR: The standard input can not be used with --checkpoint".to_string() ),
+            InputSource::File(p)  => {
+                let metadata = fs::metadata(p).map_err(|e| format!("ERROR: Reading input file: {} {}", p.display(), e))?;
+                Ok(InputIdentity {
+                    name:      p.display().to_string(),
+                    size:      metadata.len(),
+                    modified:  metadata.modified().ok()
+                                                  .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                                                  .map(|d| d.as_secs()),
+                })
+            },
+        }
+    }
+}
+
+/**
+ * Position in an input after the rows already applied, so a resumed run continues there
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InputPosition {
+    // Bytes of the decoded text, lines and CSV records before the position
+    offset:           u64,
+    line:             u64,
+    #[serde(default)]
+    record:           u64,
+    // Columns of a CSV input, as its header is not read again
+    #[serde(default)]
+    columns:          Vec<String>,
+}
+
+/**
+ * Progress of a run. It is the first line of the checkpoint file, the accounts follow it.
+ * The transactions are in the log of the checkpoint, one line with the changes of the
+ * store per checkpoint
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Progress {
+    version:          u32,
+    inputs:           Vec<InputIdentity>,
+    // Input being processed, and number of its rows already applied
+    input_index:      usize,
+    rows:             u64,
+    // Where the input continues. None if it can not be resumed at a position, i.e. a
+    // JSON array, so its rows are read again and skipped
+    position:         Option<InputPosition>,
+    error_count:      usize,
+    // Rejects file at the checkpoint, so the rows rejected after it can be removed
+    rejects_size:     Option<u64>,
+    rejects_columns:  Option<Vec<String>>,
+    // Size of the log at the checkpoint. The changes after it are removed
+    log_size:         u64,
+}
+
+/**
+ * Where and how often checkpoints are written
+ */
+struct Checkpointer {
+    path:          String,
+    every:         u64,
+    inputs:        Vec<InputIdentity>,
+    since_last:    u64,
+    // Log of the transactions, and changes of the store since the last checkpoint
+    log:           File,
+    changes:       StoreChanges,
+}
+
+/**
+ * Log of the transactions of a checkpoint file
+ */
+fn checkpoint_log_path(in_path: &str) -> String {
+    format!("{}.log", in_path)
+}
+
+/**
+ * Read a checkpoint file. The engine takes the accounts saved in it and the transactions
+ * of its log
+ */
+fn read_checkpoint(in_path: &str, in_engine: &mut PaymentEngine) -> Result<Progress, String> {
+    let f = File::open(in_path).map_err(|e| format!("ERROR: Opening checkpoint file: {} {}", in_path, e))?;
+    let mut reader = BufReader::new(f);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)
+          .map_err(|e| format!("ERROR: Reading checkpoint file: {} {}", in_path, e))?;
+    let progress: Progress = serde_json::from_str(&line)
+                                        .map_err(|e| format!("ERROR: Reading checkpoint file: {} {}", in_path, e))?;
+    if progress.version != CHECKPOINT_VERSION {
+        return Err( format!("ERROR: Unsupported checkpoint file version: {} {}", in_path, progress.version) );
+    }
+
+    in_engine.load_accounts(reader)
+             .map_err(|e| format!("ERROR: Reading checkpoint file: {} {}", in_path, e))?;
+
+    let log_path = checkpoint_log_path(in_path);
+    let log = File::open(&log_path).map_err(|e| format!("ERROR: Opening checkpoint file: {} {}", log_path, e))?;
+    for line in BufReader::new(log.take(progress.log_size)).lines() {
+        line.and_then(|l| serde_json::from_str::<StoreState>(&l).map_err(io::Error::from))
+            .and_then(|c| in_engine.import_transactions(&c))
+            .map_err(|e| format!("ERROR: Reading checkpoint file: {} {}", log_path, e))?;
+    }
+    Ok(progress)
+}
+
+/**
+ * State of a batch run: the engine, the rejects file, the number of rejected rows and
+ * the position in the inputs
+ */
+struct Batch {
+    engine:        PaymentEngine,
+    rejects:       Option<RejectsWriter>,
+    error_count:   usize,
+    // Input being processed and number of its rows already applied
+    input_index:   usize,
+    rows:          u64,
+    // Position after the row being applied, if the input can be resumed at a position
+    position:      Option<InputPosition>,
+    // Where the current input continues, when a run is resumed. Otherwise, the rows
+    // applied before the checkpoint are skipped
+    resume_at:     Option<InputPosition>,
+    skip_rows:     u64,
+    checkpoint:    Option<Checkpointer>,
+    // Worker threads, in parallel mode. The engine is split between them while they run
+    shards:        Option<ParallelEngine>,
+    // Name and columns of the current input, shared by its rows sent to the threads
+    shard_input:   Option<(Arc<str>, Arc<ByteRecord>)>,
+}
+
+impl Batch {
+    /**
+     * Start processing an input
+     */
+    fn start_input(&mut self, in_index: usize) {
+        if in_index != self.input_index {
+            self.resume_at = None;
+            self.skip_rows = 0;
+            self.rows = 0;
+        }
+        self.input_index = in_index;
+        self.position = None;
+    }
+
+    /**
+     * Apply one decoded row to the engine. A rejected row is reported, counted and written
+     * to the rejects file. It fails if the error policy stops the processing
+     */
+    fn apply_row(&mut self, in_config: &Config, in_source: &str, in_line: u64, in_headers: &ByteRecord, in_record: &ByteRecord,
+                 in_tx: Result<Transaction, Rejection>) -> Result<(), String> {
+        // Already applied before the checkpoint
+        if self.skip_rows > 0 {
+            self.skip_rows -= 1;
+            self.rows += 1;
+            return Ok(());
+        }
+
+        if self.shards.is_some() && self.shard_input.as_ref().is_none_or(|(name, _)| &**name != in_source) {
+            self.shard_input = Some( (Arc::from(in_source), Arc::new(in_headers.clone())) );
+        }
+
+        let result = match (in_tx, self.shards.as_mut(), self.shard_input.as_ref()) {
+            (Ok(tx), Some(pool), Some((source, columns))) => {
+                // Applied by a worker thread, which reports it if it is rejected
+                pool.apply(ParallelRow { tx, source: source.clone(), line: in_line, columns: columns.clone(), record: in_record.clone() })
+                    .map_err(|e| format!("ERROR: {}", e))?;
+                Ok(())
+            },
+            (Ok(tx), _, _) => match self.engine.apply_from(&tx, &SourceRow { source: in_source, line: in_line, columns: in_headers, record: in_record }) {
+                Ok(replayed_list) => {
+                    self.reject_replayed(in_config, &replayed_list)?;
+                    Ok(())
+                },
+                // The store can not be trusted anymore
+                Err(e @ EngineError::Storage { .. }) => return Err( format!("{}:{}: ERROR: {}", in_source, in_line, e) ),
+                Err(e)                               => Err( Rejection::from(e) ),
+            },
+            (Err(e), _, _) => Err(e),
+        };
 
-            // Add the Transaction
-            if let Err(e) = add_transaction(in_current_tx, in_transaction_list) {
-                return Err(e);
+        if let Err(e) = result {
+            self.reject(in_config, in_source, in_line, in_headers, in_record, &e)?;
+        }
+        self.collect_shard_results(in_config)?;
+
+        self.rows += 1;
+        if let Some(c) = self.checkpoint.as_mut() {
+            c.since_last += 1;
+            if c.since_last >= c.every {
+                self.write_checkpoint()?;
             }
-        },
+        }
 
-        // -------------------------------------
-        "dispute" => {
-            // Search for client
-            let mut the_client : ClientAccount;
-            match get_add_client(in_current_tx.client_id, in_client_list) {
-                Ok(c)  => the_client = c,
-                Err(e) => { return Err(e); },
-            };
+        Ok(())
+    }
 
-            // Get the previous transaction
-            let previous_tx = in_transaction_list.get(&in_current_tx.tx_id);
-            if let Some(p) = previous_tx {
-                // Decrease client available fnds and increase held funds
-                the_client.available -= p.amount;
-                the_client.held      += p.amount;
+    /**
+     * Report, count and write to the rejects file a rejected row. It fails if the error 
+     * policy stops the processing
+     */
+    fn reject(&mut self, in_config: &Config, in_source: &str, in_line: u64, in_headers: &ByteRecord, in_record: &ByteRecord,
+              in_rejection: &Rejection) -> Result<(), String> {
+        self.error_count += 1;
+        eprintln!("{}:{}: {}", in_source, in_line, in_rejection.message);
 
-                // Update the client
-                if let Some(c) = in_client_list.get_mut(&in_current_tx.client_id) {
-                    *c = the_client;
-                }
+        if let Some(w) = self.rejects.as_mut() {
+            w.write(in_headers, in_record, in_source, in_line, in_rejection)?;
+        }
 
-                // Add the Transaction
-                if let Err(e) = add_transaction(in_current_tx, in_transaction_list) {
-                    return Err(e);
-                }
+        if in_config.error_policy.must_stop(self.error_count) {
+            return Err( format!("ERROR: Processing aborted after {} invalid transactions", self.error_count) );
+        }
+        Ok(())
+    }
+
+    /**
+     * Report the queued operations rejected when their account is unlocked, with the
+     * input and line they were read from
+     */
+    fn reject_replayed(&mut self, in_config: &Config, in_replayed_list: &[ReplayedTransaction]) -> Result<(), String> {
+        for replayed in in_replayed_list {
+            let queued = &replayed.queued;
+            match &replayed.result {
+                Ok(())                               => {},
+                Err(e @ EngineError::Storage { .. }) => return Err( format!("{}:{}: ERROR: {}", queued.source, queued.line, e) ),
+                Err(e)                               => {
+                    self.reject(in_config, &queued.source, queued.line, &queued.columns, &queued.record, &Rejection::from(e.clone()))?;
+                },
             }
+        }
+        Ok(())
+    }
 
-            // If previous transaction does not exist, it will be ignored
-        },
+    /**
+     * Report the operations still queued on locked accounts at the end of the run.
+     * Without a state file they will never be applied, so they are rejected
+     */
+    fn reject_pending(&mut self, in_config: &Config) -> Result<(), String> {
+        let queued_list: Vec<QueuedTransaction> = self.engine.snapshot()
+                                                             .iter()
+                                                             .flat_map(|a| a.pending().to_vec())
+                                                             .collect();
 
-        // -------------------------------------
-        "resolve" => {
-            // Search for client
-            let mut the_client : ClientAccount;
-            match get_add_client(in_current_tx.client_id, in_client_list) {
-                Ok(c)  => the_client = c,
-                Err(e) => { return Err(e); },
-            };
+        for queued in queued_list {
+            let error = EngineError::AccountLocked { client_id: queued.tx.client_id, tx_id: queued.tx.tx_id };
+            self.reject(in_config, &queued.source, queued.line, &queued.columns, &queued.record, &Rejection::from(error))?;
+        }
+        Ok(())
+    }
 
-            // Get the previous transaction
-            let previous_tx = in_transaction_list.get(&in_current_tx.tx_id);
-            if let Some(p) = previous_tx {
-                // Check if prevous transaction was 'dispute'
-                if p.type_name == "dispute" {
-                    // Decrease client held funds and increase the available funds
-                    the_client.available += p.amount;
-                    the_client.held      -= p.amount;
-    
-                    // Update the client
-                    if let Some(c) = in_client_list.get_mut(&in_current_tx.client_id) {
-                        *c = the_client;
-                    }
-    
-                    // Add the Transaction
-                    if let Err(e) = add_transaction(in_current_tx, in_transaction_list) {
-                        return Err(e);
+    /**
+     * Split the engine between the given number of worker threads
+     */
+    fn start_shards(&mut self, in_threads: usize) {
+        let engine = std::mem::take(&mut self.engine);
+        self.shards = Some( ParallelEngine::start(engine, in_threads) );
+    }
+
+    /**
+     * Report the rows rejected by the worker threads so far
+     */
+    fn collect_shard_results(&mut self, in_config: &Config) -> Result<(), String> {
+        let results = match self.shards.as_ref() {
+            Some(pool) => pool.results(),
+            None       => return Ok(()),
+        };
+        self.report_shard_results(in_config, results)
+    }
+
+    fn report_shard_results(&mut self, in_config: &Config, in_results: Vec<ParallelResult>) -> Result<(), String> {
+        for result in in_results {
+            match result {
+                ParallelResult::Rejected(row, error) => {
+                    if let EngineError::Storage { .. } = error {
+                        return Err( format!("{}:{}: ERROR: {}", row.source, row.line, error) );
                     }
-                }
+                    self.reject(in_config, &row.source, row.line, &row.columns, &row.record, &Rejection::from(error))?;
+                },
+                ParallelResult::Replayed(replayed_list) => self.reject_replayed(in_config, &replayed_list)?,
             }
+        }
+        Ok(())
+    }
 
-            // If previous transaction does not exist or was not it "dispute", it will be ignored
-        },
+    /**
+     * Wait for the worker threads and join their engines back into one
+     */
+    fn finish_shards(&mut self, in_config: &Config) -> Result<(), String> {
+        let (engine, results) = match self.shards.take() {
+            Some(pool) => pool.finish().map_err(|e| format!("ERROR: {}", e))?,
+            None       => return Ok(()),
+        };
+        self.engine = engine;
+        self.shard_input = None;
 
-        // -------------------------------------
-        "chargeback" => {
-            // Search for client
-            let mut the_client : ClientAccount;
-            match get_add_client(in_current_tx.client_id, in_client_list) {
-                Ok(c)  => the_client = c,
-                Err(e) => { return Err(e); },
-            };
+        self.report_shard_results(in_config, results)
+    }
 
+    /**
+     * Save the progress and the state of the engine, so the run can be resumed from here
+     */
+    fn write_checkpoint(&mut self) -> Result<(), String> {
+        let (rejects_size, rejects_columns) = match self.rejects.as_mut() {
+            Some(w) => {
+                let (size, columns) = w.position()?;
+                (Some(size), columns)
+            },
+            None    => (None, None),
+        };
 
-            // Get the previous transaction
-            let previous_tx = in_transaction_list.get(&in_current_tx.tx_id);
-            if let Some(p) = previous_tx {
-                 // Check if prevous transaction was 'dispute'
-                 if p.type_name == "dispute" {
-                    // Decrease client held funds and increase the available funds
-                    the_client.held      -= p.amount;
-                    the_client.total     -= p.amount;
-                    // Lock the account
-                    the_client.locked     = true;
-
-                    // Update the client
-                    if let Some(c) = in_client_list.get_mut(&in_current_tx.client_id) {
-                        *c = the_client;
-                    }
+        let checkpoint = match self.checkpoint.as_mut() {
+            Some(c) => c,
+            None    => return Ok(()),
+        };
 
-                    // Add the Transaction
-                    if let Err(e) = add_transaction(in_current_tx, in_transaction_list) {
-                        return Err(e);
-                    }
+        // Only the changes of the store since the last checkpoint are written
+        let path = &checkpoint.path;
+        let log = &mut checkpoint.log;
+        let changes = checkpoint.changes.take();
+        if !changes.ids.is_empty() || !changes.deposits.is_empty() {
+            let mut w = BufWriter::new(&mut *log);
+            serde_json::to_writer(&mut w, &changes).map_err(io::Error::from)
+                       .and_then(|_| writeln!(w))
+                       .and_then(|_| w.flush())
+                       .map_err(|e| format!("ERROR: Writing checkpoint file: {} {}", checkpoint_log_path(path), e))?;
+        }
+        let log_size = log.sync_data()
+                          .and_then(|_| log.stream_position())
+                          .map_err(|e| format!("ERROR: Writing checkpoint file: {} {}", checkpoint_log_path(path), e))?;
+
+        let progress = Progress {
+            version:      CHECKPOINT_VERSION,
+            inputs:       checkpoint.inputs.clone(),
+            input_index:  self.input_index,
+            rows:         self.rows,
+            position:     self.position.clone(),
+            error_count:  self.error_count,
+            rejects_size,
+            rejects_columns,
+            log_size,
+        };
+
+        let engine = &self.engine;
+        write_file_atomically(path, |mut w| {
+            serde_json::to_writer(&mut w, &progress)
+                       .map_err(|e| format!("ERROR: Writing checkpoint file: {} {}", path, e))?;
+            writeln!(w).map_err(|e| format!("ERROR: Writing checkpoint file: {} {}", path, e))?;
+            engine.save_accounts(&mut w)
+                  .map_err(|e| format!("ERROR: Writing checkpoint file: {} {}", path, e))?;
+            Ok(w)
+        })?;
+
+        checkpoint.since_last = 0;
+        Ok(())
+    }
+}
+
+/**
+ * Prepare the run: the engine, with the state of previous runs, and the rejects file.
+ * A resumed run takes them from the checkpoint
+ */
+fn create_batch(in_config: &Config, in_sources: &[InputSource]) -> Result<Batch, String> {
+    let (mut engine, changes) = create_engine(in_config)?;
+
+    let (checkpoint, progress) = match (&in_config.checkpoint_file, changes) {
+        (Some(path), Some(changes)) => {
+            let inputs = in_sources.iter().map(InputIdentity::of).collect::<Result<Vec<_>, String>>()?;
+
+            let progress = if in_config.resume {
+                let progress = read_checkpoint(path, &mut engine)?;
+                if progress.inputs != inputs {
+                    return Err( format!("ERROR: The inputs are not the ones of the checkpoint: {}", path) );
                 }
+                // They come from the log, they are not changes
+                changes.take();
+                Some(progress)
+            } else {
+                None
+            };
 
-                // If previous transaction does not exist or was not it "dispute", it will be ignored
-            }
+            // The changes after the checkpoint are removed
+            let log_path = checkpoint_log_path(path);
+            let mut log = fs::OpenOptions::new().write(true).create(true).truncate(false).open(&log_path)
+                                            .map_err(|e| format!("ERROR: Creating checkpoint file: {} {}", log_path, e))?;
+            log.set_len(progress.as_ref().map_or(0, |p| p.log_size))
+               .and_then(|_| log.seek(SeekFrom::End(0)))
+               .map_err(|e| format!("ERROR: Creating checkpoint file: {} {}", log_path, e))?;
+
+            let checkpoint = Checkpointer {
+                path:        path.clone(),
+                every:       in_config.checkpoint_every,
+                inputs,
+                since_last:  0,
+                log,
+                changes,
+            };
+            (Some(checkpoint), progress)
         },
+        _ => (None, None),
+    };
+
+    // Rejected rows are written with the input columns plus the line number and the reason
+    let rejects = match (&in_config.rejects_file, &progress) {
+        (Some(path), Some(Progress { rejects_size: Some(size), rejects_columns, .. })) => {
+            Some( RejectsWriter::reopen(path, *size, rejects_columns.clone())? )
+        },
+        (Some(path), _) => Some( RejectsWriter::create(path)? ),
+        (None, _)       => None,
+    };
+
+    Ok(Batch {
+        engine,
+        rejects,
+        error_count:  progress.as_ref().map_or(0, |p| p.error_count),
+        input_index:  progress.as_ref().map_or(0, |p| p.input_index),
+        rows:         progress.as_ref().filter(|p| p.position.is_some()).map_or(0, |p| p.rows),
+        position:     None,
+        resume_at:    progress.as_ref().and_then(|p| p.position.clone()),
+        skip_rows:    progress.as_ref().filter(|p| p.position.is_none()).map_or(0, |p| p.rows),
+        checkpoint,
+        shards:       None,
+        shard_input:  None,
+    })
+}
 
-        _ => {
-            // Error
-            return Err( format!("ERROR: Unknown transaction type: {}", in_current_tx.type_name.as_str() ) );
+/**
+ * Process all the transactions of one input. Rejected rows are reported and counted.
+ * It fails if the input can not be read or the error policy stops the processing
+ */
+fn process_input(in_source: &InputSource, in_config: &Config, in_batch: &mut Batch) -> Result<(), String> {
+    let resume_at = in_batch.resume_at.take();
+    let reader = match &resume_at {
+        Some(p) => in_source.open_at(in_config.encoding, p.offset)?,
+        None    => in_source.open(in_config.encoding)?,
+    };
+
+    match in_source.format(in_config) {
+        DataFormat::Csv    => process_csv_input(reader, &in_source.name(), in_config, in_batch, resume_at),
+        DataFormat::Ndjson => process_ndjson_input(reader, &in_source.name(), in_config, in_batch, resume_at),
+        DataFormat::Json   => process_json_input(reader, &in_source.name(), in_config, in_batch),
+    }
+}
+
+fn process_csv_input(in_reader: Box<dyn Read>, in_source: &str, in_config: &Config, in_batch: &mut Batch,
+                     in_resume_at: Option<InputPosition>) -> Result<(), String> {
+    let dialect = &in_config.dialect;
+
+    let mut csv_reader = csv::ReaderBuilder::new()
+    //                                 .ascii()
+                                     // Remove spaces
+                                     .trim(Trim::All)
+                                     // Allow rows without the amount column
+                                     .flexible(true)
+                                     .delimiter(dialect.delimiter)
+                                     .quote(dialect.quote)
+                                     .comment(dialect.comment)
+                                     // A resumed input starts after the header
+                                     .has_headers(dialect.has_header && in_resume_at.is_none())
+                                     .from_reader( SkippedLines::new(in_reader, dialect.comment) );
+
+    let file_headers = if let Some(p) = &in_resume_at {
+        StringRecord::from(p.columns.clone())
+    } else if dialect.has_header {
+        let byte_headers = csv_reader.byte_headers()
+                                     .map_err(|e| format!("ERROR: Reading CSV header: {} {}", in_source, e))?
+                                     .clone();
+        StringRecord::from_byte_record(byte_headers)
+                     .map_err(|e| format!("ERROR: Reading CSV header: {} {}", in_source, e))?
+    } else {
+        StringRecord::from(dialect.columns.clone())
+    };
+
+    // Columns are identified by their standard names from now on, also in the rejects file
+    let headers = dialect.map_headers(&file_headers);
+    let byte_headers = headers.as_byte_record().clone();
+
+    let type_column = headers.iter().position(|h| h == "type");
+
+    if let Some(w) = in_batch.rejects.as_mut() {
+        w.set_columns(&byte_headers)?;
+    }
+
+    // Positions of the reader are relative to where it starts
+    let start = in_resume_at.unwrap_or(InputPosition { offset: 0, line: 0, record: 0, columns: Vec::new() });
+    in_batch.position = Some(InputPosition {
+        columns:  file_headers.iter().map(String::from).collect(),
+        ..start
+    });
+
+    let mut current_record = ByteRecord::new();
+    // If the record can not be read, the rest of the file can not be read either
+    while csv_reader.read_byte_record(&mut current_record).map_err(|e| format!("ERROR: Reading input file: {} {}", in_source, e))? {
+        if let Some(p) = current_record.position() {
+            // The position is where the reader started to look for the row
+            let (skipped, offset) = csv_reader.get_mut().skip(p.byte());
+            let mut absolute = p.clone();
+            absolute.set_byte(start.offset + offset).set_line(start.line + p.line() + skipped).set_record(start.record + p.record());
+            current_record.set_position(Some(absolute));
+        }
+        let line = current_record.position().map_or(0, |p| p.line());
+
+        if let Some(p) = in_batch.position.as_mut() {
+            let next = csv_reader.position();
+            p.offset = start.offset + next.byte();
+            p.line   = start.line + next.line() - 1;
+            p.record = start.record + next.record();
+        }
+
+        // Extract next transaction and process it. Process the transaction type and update client account
+        let tx = read_transaction(&current_record, &headers, type_column, &in_config.aliases);
+        in_batch.apply_row(in_config, in_source, line, &byte_headers, &current_record, tx)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Reader that keeps where the lines skipped by the CSV reader are: empty lines, and
+ * comments if there is a comment character. The CSV reader gives a row the position where
+ * it started to look for it, so these lines are needed to find the line of the row
+ */
+struct SkippedLines<R> {
+    inner:         R,
+    comment:       Option<u8>,
+    offset:        u64,
+    at_line_start: bool,
+    // Start of the current line, if it is skipped
+    current:       Option<u64>,
+    // Start and end of the skipped lines not passed yet
+    lines:         VecDeque<(u64, u64)>,
+}
+
+impl<R: Read> SkippedLines<R> {
+    fn new(in_inner: R, in_comment: Option<u8>) -> Self {
+        SkippedLines {
+            inner:          in_inner,
+            comment:        in_comment,
+            offset:         0,
+            at_line_start:  true,
+            current:        None,
+            lines:          VecDeque::new(),
+        }
+    }
+
+    /**
+     * Number of lines skipped from the given position, and where the next row starts
+     */
+    fn skip(&mut self, in_offset: u64) -> (u64, u64) {
+        let (mut skipped, mut offset) = (0, in_offset);
+
+        while let Some(&(start, end)) = self.lines.front() {
+            if start > offset {
+                break;
+            }
+            if start == offset {
+                skipped += 1;
+                offset = end;
+            }
+            self.lines.pop_front();
         }
+        (skipped, offset)
     }
+}
+
+impl<R: Read> Read for SkippedLines<R> {
+    fn read(&mut self, out_buffer: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(out_buffer)?;
+
+        for byte in &out_buffer[..size] {
+            if self.at_line_start && (*byte == b'\n' || *byte == b'\r' || Some(*byte) == self.comment) {
+                self.current = Some(self.offset);
+            }
+            self.offset += 1;
+            self.at_line_start = *byte == b'\n';
 
-    Ok(0)
+            if *byte == b'\n' {
+                if let Some(start) = self.current.take() {
+                    self.lines.push_back((start, self.offset));
+                }
+            }
+        }
+        Ok(size)
+    }
 }
 
 /**
- * Write the final status of clients' accounts to the screen
+ * Newline delimited JSON: one transaction object per line. Empty lines are ignored
  */
-fn write_accounts(in_accounts: &HashMap<u16, ClientAccount>) -> Result<(), String> {
-    if in_accounts.is_empty() == true {
-        // Nothing to be done
-        () 
+fn process_ndjson_input(in_reader: Box<dyn Read>, in_source: &str, in_config: &Config, in_batch: &mut Batch,
+                        in_resume_at: Option<InputPosition>) -> Result<(), String> {
+    let headers = json_headers();
+    if let Some(w) = in_batch.rejects.as_mut() {
+        w.set_columns(&headers)?;
     }
 
-    // Write to screen
-    let mut csv_writer = csv::Writer::from_writer( io::stdout() );
-    // let mut csv_writer = csv::WriterBuilder::new()
-    //                                 .has_headers(true)
-    //                                 .from_writer( io::stdout() );
-    
-    csv_writer.write_record(&["client", "available", "held", "total", "locked"]).unwrap();
+    let mut reader = BufReader::new(in_reader);
+    let mut buffer = Vec::new();
+    let (mut offset, mut line) = in_resume_at.map_or((0, 0), |p| (p.offset, p.line));
 
-    for current_client in in_accounts {
+    loop {
+        buffer.clear();
+        let size = reader.read_until(b'\n', &mut buffer)
+                         .map_err(|e| format!("ERROR: Reading input file: {} {}", in_source, e))?;
+        if size == 0 {
+            break;
+        }
+        offset += size as u64;
+        line += 1;
+        in_batch.position = Some(InputPosition { offset, line, record: 0, columns: Vec::new() });
 
-        let tmp_available = format!("{:.4}", current_client.1.available);
-        let tmp_held      = format!("{:.4}", current_client.1.held);
-        let tmp_total     = format!("{:.4}", current_client.1.total);
+        if buffer.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
 
-        csv_writer.serialize((current_client.1.client_id, 
-                              tmp_available, 
-                              tmp_held,
-                              tmp_total,
-                              current_client.1.locked)).unwrap();
-    
-        // if let Err(e) = csv_writer.serialize( current_client.1 ) {
-        //     return Err( e.to_string() );
-        // }
+        let (record, tx) = match std::str::from_utf8(&buffer) {
+            Ok(text) => read_json_transaction(text, &in_config.aliases),
+            Err(e)   => (ByteRecord::new(), Err( Rejection::new("invalid_encoding", format!("ERROR: Invalid character encoding in transaction: {}", e)) )),
+        };
+        in_batch.apply_row(in_config, in_source, line, &headers, &record, tx)?;
     }
-    csv_writer.flush().expect("ERROR: Writing data to screen");
 
-    Ok(())        
+    Ok(())
+}
+
+/**
+ * JSON array of transaction objects. The whole array is read before processing it, so
+ * big inputs should use NDJSON. The position reported for each transaction is its index 
+ * in the array, starting at 1
+ */
+fn process_json_input(in_reader: Box<dyn Read>, in_source: &str, in_config: &Config, in_batch: &mut Batch) -> Result<(), String> {
+    let headers = json_headers();
+    if let Some(w) = in_batch.rejects.as_mut() {
+        w.set_columns(&headers)?;
+    }
+
+    let values: Vec<Box<RawValue>> = serde_json::from_reader(BufReader::new(in_reader))
+                                                .map_err(|e| format!("ERROR: Reading input file: {} {}", in_source, e))?;
+
+    for (index, raw_value) in values.into_iter().enumerate() {
+        let (record, tx) = read_json_transaction(raw_value.get(), &in_config.aliases);
+        in_batch.apply_row(in_config, in_source, index as u64 + 1, &headers, &record, tx)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Write the final status of clients' accounts, in the given order. It returns the
+ * writer, so the caller can finish it
+ */
+fn write_accounts<W: Write>(in_writer: W, in_engine: &PaymentEngine, in_order: AccountOrder, in_format: DataFormat) -> Result<W, String> {
+    let mut accounts = in_engine.snapshot();
+    in_order.sort(&mut accounts);
+
+    let result = match in_format {
+        DataFormat::Csv    => write_csv_accounts(in_writer, &accounts),
+        DataFormat::Json   => write_json_accounts(in_writer, &accounts),
+        DataFormat::Ndjson => write_ndjson_accounts(in_writer, &accounts),
+    };
+    result.map_err(|e| format!("ERROR: Writing accounts: {}", e))
+}
+
+/**
+ * Write a file through a temporary file in the same folder, which replaces it only when 
+ * it is complete. So a failed run never leaves a partial file
+ */
+fn write_file_atomically<F>(in_path: &str, in_write: F) -> Result<(), String>
+    where F: FnOnce(BufWriter<File>) -> Result<BufWriter<File>, String> {
+    let path = Path::new(in_path);
+    let file_name = path.file_name()
+                        .ok_or_else(|| format!("ERROR: Invalid output file: {}", in_path))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = File::create(&tmp_path)
+                    .map_err(|e| format!("ERROR: Creating output file: {} {}", tmp_path.display(), e))
+                    .and_then(|f| in_write(BufWriter::new(f)))
+                    .and_then(|w| w.into_inner().map_err(|e| format!("ERROR: Writing output file: {} {}", tmp_path.display(), e.error())))
+                    .and_then(|f| f.sync_all().map_err(|e| format!("ERROR: Writing output file: {} {}", tmp_path.display(), e)))
+                    .and_then(|_| fs::rename(&tmp_path, path).map_err(|e| format!("ERROR: Replacing output file: {} {}", in_path, e)));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/**
+ * Write the accounts to a file. The file is only replaced when all of them have been written
+ */
+fn write_accounts_file(in_path: &str, in_engine: &PaymentEngine, in_order: AccountOrder, in_format: DataFormat) -> Result<(), String> {
+    write_file_atomically(in_path, |w| write_accounts(w, in_engine, in_order, in_format))
+}
+
+/**
+ * Save the state of the engine, so the next run continues the same ledger
+ */
+fn save_state_file(in_path: &str, in_engine: &mut PaymentEngine) -> Result<(), String> {
+    write_file_atomically(in_path, |mut w| {
+        in_engine.save_state(&mut w)
+                 .map_err(|e| format!("ERROR: Writing state file: {} {}", in_path, e))?;
+        Ok(w)
+    })
+}
+
+/**
+ * Engine with the transaction store selected in the command line. With checkpoints, the
+ * changes of the store are recorded, so each checkpoint only saves the new ones
+ */
+fn create_engine(in_config: &Config) -> Result<(PaymentEngine, Option<StoreChanges>), String> {
+    let store: Box<dyn TransactionStore> = match (&in_config.store_file, in_config.deposit_limit) {
+        (Some(path), _) => Box::new( DiskStore::open(path).map_err(|e| format!("ERROR: Opening transaction store: {} {}", path, e))? ),
+        (None, Some(n)) => Box::new( MemoryStore::with_limit(n) ),
+        (None, None)    => Box::new( MemoryStore::new() ),
+    };
+
+    let (store, changes): (Box<dyn TransactionStore>, Option<StoreChanges>) = match in_config.checkpoint_file {
+        Some(_) => {
+            let store = RecordingStore::new(store);
+            let changes = store.changes();
+            (Box::new(store), Some(changes))
+        },
+        None    => (store, None),
+    };
+
+    let mut engine = PaymentEngine::with_store(in_config.locked_policy, store);
+
+    // There is no state file in the first run. A resumed run takes the state of the checkpoint
+    if let Some(path) = in_config.state_file.as_ref().filter(|_| !in_config.resume) {
+        if Path::new(path).exists() {
+            let f = File::open(path).map_err(|e| format!("ERROR: Opening state file: {} {}", path, e))?;
+            engine.load_state(BufReader::new(f))
+                  .map_err(|e| format!("ERROR: Reading state file: {} {}", path, e))?;
+        } else {
+            engine.start_ledger()
+                  .map_err(|e| format!("ERROR: Opening transaction store: {} {}", in_config.store_file.as_deref().unwrap_or_default(), e))?;
+        }
+    }
+
+    Ok((engine, changes))
 }
 
 /**
  * @return -  0 - No error
- *           -1 - Error. Insufficient parameters or other errors
+ *            1 - Some transactions were rejected. The rest were processed
+ *           -1 - Error. Insufficient parameters, processing aborted or other errors
  */
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -315,59 +1508,142 @@ fn main() {
         process::exit(-1);
     }
 
-    // Read input CSV
-    let input_csv_file = args[1].clone();
-
-    if Path::new(&input_csv_file).exists() == false {
-        println!("ERROR: CSV file does not exist: {}", input_csv_file);
-        process::exit(-1);
+    if args[1] == "generate" {
+        if args[2..].iter().any(|a| a == "--help") {
+            generate::usage();
+            return;
+        }
+        let generate_config = match generate::parse_args(&args[2..]) {
+            Ok(c)  => c,
+            Err(e) => {
+                eprintln!("{}", e);
+                generate::usage();
+                process::exit(-1);
+            },
+        };
+        if let Err(e) = generate::run(&generate_config) {
+            eprintln!("{}", e);
+            process::exit(-1);
+        }
+        return;
     }
 
-    let input_file = match File::open(input_csv_file) {
-        Ok(f)  => f,
-        Err(e)  => {
-            println!("{}", e);
+    let config = match parse_args(&args) {
+        Ok(c)  => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage();
             process::exit(-1);
         },
     };
 
-    let mut csv_reader = csv::ReaderBuilder::new()
-    //                                 .ascii()
-                                     // Remove spaces
-                                     .trim(Trim::All)
-                                     .from_reader( input_file ) ;   
-   
-    // Process all transactions and update client accounts
-    let mut client_list : HashMap<u16, ClientAccount> = HashMap::new();
-    let mut transaction_list : HashMap<u32, Transaction> = HashMap::new();
-
-    for current_record in csv_reader.deserialize() {
-        // Extract next transaction
-        let current_tx: Transaction = match current_record {
-            Ok(r) => {
-                r
-            },
-            Err(e) => {
-                println!("ERROR: Reading or decoding transaction: {}", e);
-                process::exit(-1);
-            },
-            
-        };
-        
-        //println!("{:?}", current_tx);
-        // Process the transaction type and update client account
-        if let Err(e) = process_transaction(&current_tx, &mut client_list, &mut transaction_list) {
-            println!("{}", e);
-            break;
+    let sources = match expand_inputs(&config.input_files) {
+        Ok(s)  => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(-1);
+        },
+    };
+
+    // Process all transactions and update client accounts. The inputs are one continuous ledger
+    let mut batch = match create_batch(&config, &sources) {
+        Ok(b)  => b,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(-1);
+        },
+    };
+
+    if config.threads > 1 {
+        batch.start_shards(config.threads);
+    }
+
+    for (index, source) in sources.iter().enumerate().skip(batch.input_index) {
+        batch.start_input(index);
+        if let Err(e) = process_input(source, &config, &mut batch) {
+            eprintln!("{}", e);
+            if let Some(w) = batch.rejects.as_mut() {
+                let _ = w.flush();
+            }
+            process::exit(-1);
+        }
+    }
+
+    if let Err(e) = batch.finish_shards(&config) {
+        eprintln!("{}", e);
+        if let Some(w) = batch.rejects.as_mut() {
+            let _ = w.flush();
+        }
+        process::exit(-1);
+    }
+
+    // Queued operations are kept in the state file, otherwise they are lost
+    if config.state_file.is_none() {
+        if let Err(e) = batch.reject_pending(&config) {
+            eprintln!("{}", e);
+            if let Some(w) = batch.rejects.as_mut() {
+                let _ = w.flush();
+            }
+            process::exit(-1);
+        }
+    }
+
+    if let Some(w) = batch.rejects.as_mut() {
+        if let Err(e) = w.flush() {
+            eprintln!("{}", e);
+            process::exit(-1);
+        }
+    }
+
+    if let Err(e) = batch.engine.flush() {
+        eprintln!("ERROR: Saving transaction store: {}", e);
+        process::exit(-1);
+    }
+
+    if let Some(path) = &config.state_file {
+        if let Err(e) = save_state_file(path, &mut batch.engine) {
+            eprintln!("{}", e);
+            process::exit(-1);
         }
     }
 
+    // Until now, the changes of the transaction store are undone if the run is aborted
+    if let Err(e) = batch.engine.commit() {
+        eprintln!("ERROR: Saving transaction store: {}", e);
+        process::exit(-1);
+    }
+
     // Write output
-    if let Err(e) = write_accounts(&client_list) {
-        println!("{}", e);
+    let output_format = config.output_format
+                              .or_else(|| config.output_file.as_ref().and_then(|f| DataFormat::from_path(Path::new(f))))
+                              .unwrap_or(DataFormat::Csv);
+    let result = match &config.output_file {
+        Some(path) => write_accounts_file(path, &batch.engine, config.order, output_format),
+        None       => write_accounts(io::stdout(), &batch.engine, config.order, output_format)
+                        .and_then(|mut w| w.flush().map_err(|e| format!("ERROR: Writing accounts: {}", e))),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
         process::exit(-1);
     }
 
+    // The run is complete, it can not be resumed
+    if let Some(path) = &config.checkpoint_file {
+        for file in [path.clone(), checkpoint_log_path(path)].iter() {
+            if let Err(e) = fs::remove_file(file) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    eprintln!("ERROR: Removing checkpoint file: {} {}", file, e);
+                    process::exit(-1);
+                }
+            }
+        }
+    }
+
+    if batch.error_count > 0 {
+        // Some transactions were rejected
+        process::exit(1);
+    }
+
     // Return sucessfull
     process::exit(0);
 }
\ No newline at end of file
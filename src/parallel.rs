@@ -0,0 +1,360 @@
+/*
+ *  Parallel mode of the payment engine. The transactions are applied by several worker
+ *  threads, each one with its own engine and the clients of its shard. The result is
+ *  the same as applying them in order with one engine
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use csv::ByteRecord;
+
+use crate::engine::{LockedPolicy, PaymentEngine, ReplayedTransaction, SourceRow};
+use crate::error::EngineError;
+use crate::store::SharedStore;
+use crate::transaction::{ClientAccount, Transaction, TransactionKind};
+
+
+/// Rows sent together to a worker thread
+const BATCH_SIZE: usize = 1024;
+
+/// Batches waiting to be applied by each worker thread
+const QUEUE_SIZE: usize = 16;
+
+/// Transaction ids are grouped in pages of 2^PAGE_BITS ids
+const PAGE_BITS: u32 = 16;
+
+
+/**
+ * Transaction applied by a worker thread, with the row it was read from. It is given
+ * back if it is rejected. The name and the columns of the input are shared by its rows
+ */
+#[derive(Debug, Clone)]
+pub struct ParallelRow {
+    pub tx:        Transaction,
+    pub source:    Arc<str>,
+    pub line:      u64,
+    pub columns:   Arc<ByteRecord>,
+    pub record:    ByteRecord,
+}
+
+/**
+ * Result reported by a worker thread
+ */
+#[derive(Debug)]
+pub enum ParallelResult {
+    // Row rejected by the engine
+    Rejected(ParallelRow, EngineError),
+    // Queued operations applied by an unlock
+    Replayed(Vec<ReplayedTransaction>),
+}
+
+enum Message {
+    Rows(Vec<ParallelRow>),
+    // Reply when all the previous rows have been applied
+    Sync(mpsc::Sender<()>),
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Thread that first used each transaction id: its index plus one, or 0 if the id is not
+ * used. Ids are usually consecutive, so they are kept in pages of 65536 ids, with as few
+ * bits per id as the number of threads allows
+ */
+#[derive(Debug)]
+struct OwnerMap {
+    width:         u32,
+    pages:         HashMap<u32, Box<[u64]>>,
+}
+
+impl OwnerMap {
+    fn new(in_threads: usize) -> Self {
+        let bits = usize::BITS - in_threads.leading_zeros();
+        OwnerMap {
+            width:  bits.next_power_of_two().min(32),
+            pages:  HashMap::new(),
+        }
+    }
+
+    /**
+     * Page, word in the page and shift in the word of an id
+     */
+    fn position(&self, in_tx_id: u32) -> (u32, usize, u32) {
+        let bit = u64::from(in_tx_id & ((1 << PAGE_BITS) - 1)) * u64::from(self.width);
+        (in_tx_id >> PAGE_BITS, (bit / 64) as usize, (bit % 64) as u32)
+    }
+
+    fn get(&self, in_tx_id: u32) -> usize {
+        let (page, word, shift) = self.position(in_tx_id);
+        let mask = (1u64 << self.width) - 1;
+        self.pages.get(&page).map_or(0, |p| ((p[word] >> shift) & mask) as usize)
+    }
+
+    fn set(&mut self, in_tx_id: u32, in_owner: usize) {
+        let (page, word, shift) = self.position(in_tx_id);
+        let words = ((1usize << PAGE_BITS) * self.width as usize) / 64;
+        let mask = (1u64 << self.width) - 1;
+
+        let bits = self.pages.entry(page).or_insert_with(|| vec![0; words].into_boxed_slice());
+        bits[word] = (bits[word] & !(mask << shift)) | ((in_owner as u64 & mask) << shift);
+    }
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Engine that applies the transactions in worker threads. Clients are split between them
+ * by id, so the rows of a client are applied in order by the same engine. The engines
+ * share the transaction store, so ids are unique across all of them.
+ *
+ * Clients only interact through transaction ids. A row that uses an id first used by a
+ * client of another thread waits until all the previous rows are applied, and the next
+ * rows wait for it. So does an unlock that replays queued operations. So the result is
+ * the same as applying all the rows in order
+ */
+#[derive(Debug)]
+pub struct ParallelEngine {
+    senders:       Vec<mpsc::SyncSender<Message>>,
+    // Rows of each thread not sent yet
+    batches:       Vec<Vec<ParallelRow>>,
+    workers:       Vec<thread::JoinHandle<PaymentEngine>>,
+    results:       mpsc::Receiver<ParallelResult>,
+    store:         SharedStore,
+    locked_policy: LockedPolicy,
+    owners:        OwnerMap,
+}
+
+impl ParallelEngine {
+    /**
+     * Split an engine between the given number of worker threads
+     */
+    pub fn start(in_engine: PaymentEngine, in_threads: usize) -> Self {
+        let threads = in_threads.max(1);
+        let locked_policy = in_engine.locked_policy();
+        let (accounts, store) = in_engine.into_parts();
+        let store = SharedStore::new(store);
+
+        let mut shard_accounts: Vec<Vec<ClientAccount>> = vec![Vec::new(); threads];
+        for account in accounts {
+            shard_accounts[usize::from(account.client_id) % threads].push(account);
+        }
+
+        let (result_sender, results) = mpsc::channel();
+        let mut senders = Vec::new();
+        let mut workers = Vec::new();
+
+        for accounts in shard_accounts {
+            let (sender, receiver) = mpsc::sync_channel::<Message>(QUEUE_SIZE);
+            let mut engine = PaymentEngine::from_parts(locked_policy, accounts, Box::new(store.clone()));
+            let result_sender = result_sender.clone();
+
+            workers.push(thread::spawn(move || {
+                for message in receiver {
+                    match message {
+                        Message::Rows(rows) => {
+                            for row in rows {
+                                let source_row = SourceRow { source: &row.source, line: row.line, columns: &row.columns, record: &row.record };
+                                match engine.apply_from(&row.tx, &source_row) {
+                                    Ok(replayed_list) if replayed_list.is_empty() => {},
+                                    Ok(replayed_list) => {
+                                        let _ = result_sender.send(ParallelResult::Replayed(replayed_list));
+                                    },
+                                    Err(e) => {
+                                        let _ = result_sender.send(ParallelResult::Rejected(row, e));
+                                    },
+                                }
+                            }
+                        },
+                        Message::Sync(reply) => {
+                            let _ = reply.send(());
+                        },
+                    }
+                }
+                engine
+            }));
+            senders.push(sender);
+        }
+
+        ParallelEngine {
+            batches:        (0..threads).map(|_| Vec::with_capacity(BATCH_SIZE)).collect(),
+            senders,
+            workers,
+            results,
+            store,
+            locked_policy,
+            owners:         OwnerMap::new(threads),
+        }
+    }
+
+    /**
+     * Send a row to the thread of its client. It waits for the other threads if the row
+     * depends on them. Rows are applied later, their results are given by `results()`
+     */
+    pub fn apply(&mut self, in_row: ParallelRow) -> io::Result<()> {
+        let shard = usize::from(in_row.tx.client_id) % self.senders.len();
+
+        let depends_on_others = match in_row.tx.kind {
+            TransactionKind::Unlock => self.locked_policy == LockedPolicy::Queue,
+            _                       => self.depends_on_others(in_row.tx.tx_id, shard),
+        };
+
+        if depends_on_others {
+            self.sync()?;
+        }
+        self.batches[shard].push(in_row);
+        if depends_on_others {
+            self.sync()?;
+        } else if self.batches[shard].len() >= BATCH_SIZE {
+            self.send(shard)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Results of the rows applied since the last call
+     */
+    pub fn results(&self) -> Vec<ParallelResult> {
+        self.results.try_iter().collect()
+    }
+
+    /**
+     * Wait for all the threads and join their accounts in one engine with the store. It
+     * also returns the results not taken yet
+     */
+    pub fn finish(mut self) -> io::Result<(PaymentEngine, Vec<ParallelResult>)> {
+        for shard in 0..self.senders.len() {
+            self.send(shard)?;
+        }
+        self.senders.clear();
+
+        let mut accounts = Vec::new();
+        for worker in self.workers.drain(..) {
+            let engine = worker.join().map_err(|_| worker_error())?;
+            accounts.extend(engine.into_parts().0);
+        }
+
+        let results = self.results.try_iter().collect();
+        let store = self.store.into_inner()
+                              .map_err(|_| io::Error::other("the transaction store is still in use"))?;
+        Ok(( PaymentEngine::from_parts(self.locked_policy, accounts, store), results ))
+    }
+
+    /**
+     * True if the id was first used by a row of another thread. Otherwise it is owned
+     * by the given one
+     */
+    fn depends_on_others(&mut self, in_tx_id: u32, in_shard: usize) -> bool {
+        match self.owners.get(in_tx_id) {
+            0     => {
+                self.owners.set(in_tx_id, in_shard + 1);
+                false
+            },
+            owner => owner != in_shard + 1,
+        }
+    }
+
+    /**
+     * Send the pending rows of a thread
+     */
+    fn send(&mut self, in_shard: usize) -> io::Result<()> {
+        if self.batches[in_shard].is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::replace(&mut self.batches[in_shard], Vec::with_capacity(BATCH_SIZE));
+        self.senders[in_shard].send(Message::Rows(rows))
+                              .map_err(|_| worker_error())
+    }
+
+    /**
+     * Wait until all the rows sent have been applied
+     */
+    fn sync(&mut self) -> io::Result<()> {
+        for shard in 0..self.senders.len() {
+            self.send(shard)?;
+        }
+
+        let (reply_sender, reply) = mpsc::channel();
+        for sender in &self.senders {
+            sender.send(Message::Sync(reply_sender.clone()))
+                  .map_err(|_| worker_error())?;
+        }
+        for _ in &self.senders {
+            reply.recv().map_err(|_| worker_error())?;
+        }
+        Ok(())
+    }
+}
+
+fn worker_error() -> io::Error {
+    io::Error::other("a worker thread has failed")
+}
+
+// ---------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_map() {
+        for threads in [1, 2, 3, 7, 8, 300].iter() {
+            let mut owners = OwnerMap::new(*threads);
+            let tx_ids = [0, 1, 2, 63, 64, 65_535, 65_536, 1_000_000, u32::MAX];
+
+            for (i, tx_id) in tx_ids.iter().enumerate() {
+                assert_eq!(owners.get(*tx_id), 0);
+                owners.set(*tx_id, i % threads + 1);
+            }
+            for (i, tx_id) in tx_ids.iter().enumerate() {
+                assert_eq!(owners.get(*tx_id), i % threads + 1, "Threads: {} id: {}", threads, tx_id);
+            }
+            assert_eq!(owners.get(3), 0);
+        }
+    }
+
+    #[test]
+    fn same_accounts_as_one_engine() {
+        let columns = Arc::new(ByteRecord::new());
+        let source: Arc<str> = Arc::from("test");
+        let mut serial = PaymentEngine::with_locked_policy(LockedPolicy::Queue);
+        let mut parallel = ParallelEngine::start(PaymentEngine::with_locked_policy(LockedPolicy::Queue), 3);
+
+        // Clients dispute the deposits of others, so rows wait for other threads
+        let rows = [
+            (TransactionKind::Deposit,    1, 1, Some(100)),
+            (TransactionKind::Deposit,    2, 2, Some(50)),
+            (TransactionKind::Dispute,    2, 1, None),
+            (TransactionKind::Dispute,    1, 1, None),
+            (TransactionKind::Chargeback, 1, 1, None),
+            (TransactionKind::Deposit,    1, 3, Some(10)),
+            (TransactionKind::Withdrawal, 3, 2, Some(5)),
+            (TransactionKind::Unlock,     1, 4, None),
+            (TransactionKind::Withdrawal, 2, 5, Some(20)),
+        ];
+
+        let mut serial_errors = 0;
+        for (line, (kind, client_id, tx_id, amount)) in rows.iter().enumerate() {
+            let tx = Transaction { kind: *kind, client_id: *client_id, tx_id: *tx_id, amount: amount.map(|a| crate::Amount::from_raw(a * 10_000)) };
+            serial_errors += serial.apply(&tx).map_or(1, |r| r.iter().filter(|q| q.result.is_err()).count());
+            parallel.apply(ParallelRow { tx, source: source.clone(), line: line as u64, columns: columns.clone(), record: ByteRecord::new() }).unwrap();
+        }
+
+        let (engine, results) = parallel.finish().unwrap();
+        let parallel_errors: usize = results.iter()
+                                            .map(|r| match r {
+                                                ParallelResult::Rejected(..)      => 1,
+                                                ParallelResult::Replayed(list)    => list.iter().filter(|q| q.result.is_err()).count(),
+                                            })
+                                            .sum();
+
+        assert_eq!(format!("{:?}", engine.snapshot()), format!("{:?}", serial.snapshot()));
+        assert_eq!(parallel_errors, serial_errors);
+    }
+}
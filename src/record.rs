@@ -0,0 +1,190 @@
+/*
+ *  Rows of the inputs decoded into transactions, and accounts written as rows. Shared by
+ *  the command line tool and any other reader of the same files
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::io::{self, Write};
+
+use csv::{ByteRecord, StringRecord};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+use crate::error::EngineError;
+use crate::transaction::{ClientAccount, KindAliases, Transaction};
+
+
+/// Columns of the accounts in CSV
+const ACCOUNT_COLUMNS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// Columns of a transaction. They are the names of the fields of a JSON transaction
+pub const TRANSACTION_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+
+/**
+ * Reason why a row is rejected, either because it can not be decoded or because
+ * the engine rejects it. The code is a short stable identifier written to the
+ * rejects file, the message is meant for humans
+ */
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub code:      &'static str,
+    pub message:   String,
+}
+
+impl Rejection {
+    pub fn new(in_code: &'static str, in_message: String) -> Self {
+        Rejection {
+            code:     in_code,
+            message:  in_message,
+        }
+    }
+}
+
+impl From<EngineError> for Rejection {
+    fn from(in_error: EngineError) -> Self {
+        Rejection::new(in_error.code(), format!("ERROR: {}", in_error))
+    }
+}
+
+/**
+ * Decode a CSV row into a transaction, given the standard names of its columns. The type
+ * is resolved first, with the aliases, so an unknown type is reported with its value
+ */
+pub fn read_transaction(in_record: &ByteRecord, in_headers: &StringRecord, in_type_column: Option<usize>, in_aliases: &KindAliases) -> Result<Transaction, Rejection> {
+    let mut record = StringRecord::from_byte_record(in_record.clone())
+                        .map_err(|e| Rejection::new("invalid_encoding", format!("ERROR: Invalid character encoding in transaction: {}", e)))?;
+
+    if let Some(type_text) = in_type_column.and_then(|i| record.get(i)) {
+        let kind = in_aliases.resolve(type_text)
+                             .map_err(|e| Rejection::new("unknown_type", format!("ERROR: {}", e)))?;
+
+        // Replace an alias by the standard name
+        if !kind.name().eq_ignore_ascii_case(type_text) {
+            record = record.iter()
+                           .enumerate()
+                           .map(|(i, field)| if Some(i) == in_type_column { kind.name() } else { field })
+                           .collect();
+        }
+    }
+
+    record.deserialize::<Transaction>(Some(in_headers))
+          .map_err(|e| Rejection::new("invalid_record", format!("ERROR: Reading or decoding transaction: {}", e)))
+}
+
+/**
+ * Columns of the rows of JSON transactions
+ */
+pub fn json_headers() -> ByteRecord {
+    ByteRecord::from(TRANSACTION_COLUMNS.to_vec())
+}
+
+/**
+ * Decode a JSON transaction object. It follows the same rules as a CSV row: the type is
+ * resolved with the aliases, and an amount given as a JSON number is read from its text,
+ * not as a floating point number. It also returns the fields as a row with the columns
+ * of `json_headers()`, so it can be written to the rejects file
+ */
+pub fn read_json_transaction(in_text: &str, in_aliases: &KindAliases) -> (ByteRecord, Result<Transaction, Rejection>) {
+    match parse_json_transaction(in_text) {
+        Ok(value) => (json_record(&value), json_transaction(value, in_aliases)),
+        Err(e)    => (ByteRecord::new(), Err( Rejection::new("invalid_record", format!("ERROR: Reading or decoding transaction: {}", e)) )),
+    }
+}
+
+/**
+ * Fields of a JSON transaction as a row
+ */
+fn json_record(in_value: &Value) -> ByteRecord {
+    TRANSACTION_COLUMNS.iter()
+                       .map(|c| match in_value.get(c) {
+                           Some(Value::String(s)) => s.clone(),
+                           Some(Value::Null) | None => String::new(),
+                           Some(other)            => other.to_string(),
+                       })
+                       .collect::<Vec<String>>()
+                       .into()
+}
+
+/**
+ * Parse a JSON transaction. An amount given as a JSON number is replaced by its text in
+ * the input
+ */
+fn parse_json_transaction(in_text: &str) -> serde_json::Result<Value> {
+    #[derive(Deserialize)]
+    struct AmountText<'a> {
+        #[serde(borrow)]
+        amount:    Option<&'a RawValue>,
+    }
+
+    let mut value: Value = serde_json::from_str(in_text)?;
+    if let Some(Value::Number(_)) = value.get("amount") {
+        let text: AmountText = serde_json::from_str(in_text)?;
+        if let (Some(amount), Value::Object(object)) = (text.amount, &mut value) {
+            object.insert("amount".to_string(), Value::String(amount.get().to_string()));
+        }
+    }
+    Ok(value)
+}
+
+fn json_transaction(in_value: Value, in_aliases: &KindAliases) -> Result<Transaction, Rejection> {
+    let mut object = match in_value {
+        Value::Object(o) => o,
+        _                => return Err( Rejection::new("invalid_record", "ERROR: Reading or decoding transaction: it is not a JSON object".to_string()) ),
+    };
+
+    if let Some(Value::String(type_text)) = object.get("type") {
+        let kind = in_aliases.resolve(type_text)
+                             .map_err(|e| Rejection::new("unknown_type", format!("ERROR: {}", e)))?;
+        object.insert("type".to_string(), Value::String(kind.name().to_string()));
+    }
+
+    serde_json::from_value::<Transaction>(Value::Object(object))
+               .map_err(|e| Rejection::new("invalid_record", format!("ERROR: Reading or decoding transaction: {}", e)))
+}
+
+/**
+ * Write the accounts as CSV. Amounts are exact, they are always written with 4 decimal
+ * digits. It returns the writer, so the caller can finish it
+ */
+pub fn write_csv_accounts<W: Write>(in_writer: W, in_accounts: &[ClientAccount]) -> io::Result<W> {
+    let mut csv_writer = csv::Writer::from_writer(in_writer);
+
+    csv_writer.write_record(ACCOUNT_COLUMNS)?;
+    for current_client in in_accounts {
+        csv_writer.serialize((current_client.client_id,
+                              current_client.available,
+                              current_client.held,
+                              current_client.total,
+                              current_client.locked))?;
+    }
+
+    csv_writer.into_inner()
+              .map_err(|e| io::Error::new(e.error().kind(), e.error().to_string()))
+}
+
+/**
+ * JSON array of account objects. Amounts are written as strings, so they keep their 4 decimal digits
+ */
+pub fn write_json_accounts<W: Write>(mut in_writer: W, in_accounts: &[ClientAccount]) -> io::Result<W> {
+    serde_json::to_writer(&mut in_writer, in_accounts)?;
+    writeln!(in_writer)?;
+
+    Ok(in_writer)
+}
+
+/**
+ * One account object per line
+ */
+pub fn write_ndjson_accounts<W: Write>(mut in_writer: W, in_accounts: &[ClientAccount]) -> io::Result<W> {
+    for current_client in in_accounts {
+        serde_json::to_writer(&mut in_writer, current_client)?;
+        writeln!(in_writer)?;
+    }
+
+    Ok(in_writer)
+}
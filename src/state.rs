@@ -0,0 +1,212 @@
+/*
+ *  State file of the engine: the client accounts and the stored transactions, so the
+ *  next run continues the same ledger
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use csv::ByteRecord;
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::store::{StoreState, TransactionStore};
+use crate::transaction::{ClientAccount, QueuedTransaction, Transaction, TransactionKind};
+
+
+/// Version of the format of the state file
+const STATE_VERSION: u32 = 1;
+
+
+/**
+ * Queued operation as saved in the state file: the transaction fields, followed by its
+ * input, line, and the row as it was read with the columns of its input
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingState {
+    #[serde(rename = "type")]
+    kind:          TransactionKind,
+    client:        u16,
+    tx:            u32,
+    #[serde(default)]
+    amount:        Option<Amount>,
+    source:        String,
+    line:          u64,
+    columns:       Vec<String>,
+    record:        Vec<String>,
+}
+
+impl From<&QueuedTransaction> for PendingState {
+    fn from(in_queued: &QueuedTransaction) -> Self {
+        PendingState {
+            kind:    in_queued.tx.kind,
+            client:  in_queued.tx.client_id,
+            tx:      in_queued.tx.tx_id,
+            amount:  in_queued.tx.amount,
+            source:  in_queued.source.clone(),
+            line:    in_queued.line,
+            columns: byte_record_fields(&in_queued.columns),
+            record:  byte_record_fields(&in_queued.record),
+        }
+    }
+}
+
+impl From<PendingState> for QueuedTransaction {
+    fn from(in_pending: PendingState) -> Self {
+        QueuedTransaction {
+            tx:       Transaction {
+                kind:       in_pending.kind,
+                client_id:  in_pending.client,
+                tx_id:      in_pending.tx,
+                amount:     in_pending.amount,
+            },
+            source:   in_pending.source,
+            line:     in_pending.line,
+            columns:  ByteRecord::from(in_pending.columns),
+            record:   ByteRecord::from(in_pending.record),
+        }
+    }
+}
+
+/**
+ * Fields of a row as text. Rows are only queued once they have been decoded, so they
+ * are valid UTF-8
+ */
+fn byte_record_fields(in_record: &ByteRecord) -> Vec<String> {
+    in_record.iter()
+             .map(|f| String::from_utf8_lossy(f).into_owned())
+             .collect()
+}
+
+/**
+ * Account as saved in the state file, with its queued operations
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccountState {
+    client:        u16,
+    available:     Amount,
+    held:          Amount,
+    total:         Amount,
+    locked:        bool,
+    #[serde(default)]
+    pending:       Vec<PendingState>,
+}
+
+/**
+ * Contents of the state file, in JSON. The transactions are not saved if the store
+ * keeps its own file, only the generation of the store that matches the accounts
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EngineState {
+    version:       u32,
+    accounts:      Vec<AccountState>,
+    transactions:  Option<StoreState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    store_generation: Option<u32>,
+}
+
+/**
+ * Write the accounts, ordered by client id, and the contents of the store
+ */
+pub(crate) fn save_state<W: Write>(in_writer: W, in_client_list: &HashMap<u16, ClientAccount>, in_store: &mut dyn TransactionStore) -> io::Result<()> {
+    let transactions = if in_store.is_persistent() {
+        None
+    } else {
+        Some(in_store.export()?)
+    };
+
+    let state = EngineState {
+        version:           STATE_VERSION,
+        accounts:          account_states(in_client_list),
+        transactions,
+        store_generation:  in_store.generation(),
+    };
+    serde_json::to_writer(in_writer, &state)?;
+    Ok(())
+}
+
+/**
+ * Write the accounts only. The transactions are saved by other means
+ */
+pub(crate) fn save_accounts<W: Write>(in_writer: W, in_client_list: &HashMap<u16, ClientAccount>) -> io::Result<()> {
+    let state = EngineState {
+        version:           STATE_VERSION,
+        accounts:          account_states(in_client_list),
+        transactions:      None,
+        store_generation:  None,
+    };
+    serde_json::to_writer(in_writer, &state)?;
+    Ok(())
+}
+
+/**
+ * Accounts as saved in the state file, ordered by client id
+ */
+fn account_states(in_client_list: &HashMap<u16, ClientAccount>) -> Vec<AccountState> {
+    let mut accounts: Vec<AccountState> = in_client_list.values()
+                                                        .map(|c| AccountState {
+                                                            client:     c.client_id,
+                                                            available:  c.available,
+                                                            held:       c.held,
+                                                            total:      c.total,
+                                                            locked:     c.locked,
+                                                            pending:    c.pending.iter().map(PendingState::from).collect(),
+                                                        })
+                                                        .collect();
+    accounts.sort_by_key(|a| a.client);
+    accounts
+}
+
+/**
+ * Read a state file. The transactions are added to the store and the accounts are returned.
+ * It fails if the transactions were kept in a store file, and the given store is not persistent
+ */
+pub(crate) fn load_state<R: Read>(in_reader: R, in_store: &mut dyn TransactionStore) -> io::Result<HashMap<u16, ClientAccount>> {
+    let state = read_state(in_reader)?;
+
+    in_store.recover(state.store_generation)?;
+
+    match &state.transactions {
+        Some(t)                            => in_store.import(t)?,
+        None if in_store.is_persistent()   => {},
+        None                               => {
+            return Err( io::Error::new(io::ErrorKind::InvalidData, "the transactions of this state are kept in a transaction store file") );
+        },
+    }
+
+    Ok( client_list(state) )
+}
+
+/**
+ * Read the accounts of a state file. The transactions, if any, are not loaded
+ */
+pub(crate) fn load_accounts<R: Read>(in_reader: R) -> io::Result<HashMap<u16, ClientAccount>> {
+    Ok( client_list(read_state(in_reader)?) )
+}
+
+fn read_state<R: Read>(in_reader: R) -> io::Result<EngineState> {
+    let state: EngineState = serde_json::from_reader(in_reader)?;
+
+    if state.version != STATE_VERSION {
+        return Err( io::Error::new(io::ErrorKind::InvalidData, format!("unsupported state file version: {}", state.version)) );
+    }
+    Ok(state)
+}
+
+fn client_list(in_state: EngineState) -> HashMap<u16, ClientAccount> {
+    in_state.accounts
+            .into_iter()
+            .map(|a| (a.client, ClientAccount {
+                client_id:  a.client,
+                available:  a.available,
+                held:       a.held,
+                total:      a.total,
+                locked:     a.locked,
+                pending:    a.pending.into_iter().map(QueuedTransaction::from).collect(),
+            }))
+            .collect()
+}
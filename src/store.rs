@@ -0,0 +1,1072 @@
+/*
+ *  Transactions kept by the engine for later reference: the ids already used and the
+ *  deposits that can be disputed. Only compact records are kept, not whole transactions.
+ *  They can be kept in memory or in a file
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+
+
+/// Transaction ids are grouped in pages of 2^PAGE_BITS ids, one bit per id
+const PAGE_BITS: u32 = 16;
+const PAGE_WORDS: usize = (1 << PAGE_BITS) / 64;
+
+/// Identifier of a transaction store file, in the SQLite header, and format version
+const DISK_APPLICATION_ID: i32 = 0x4353_5054;
+const DISK_VERSION: u32 = 1;
+const DISK_SCHEMA: &str = "CREATE TABLE store (version INTEGER NOT NULL, generation INTEGER NOT NULL, base INTEGER);
+                           CREATE TABLE transactions (tx_id INTEGER PRIMARY KEY, state INTEGER NOT NULL, client INTEGER NOT NULL,
+                                                      amount INTEGER NOT NULL, created INTEGER NOT NULL);
+                           CREATE TABLE journal (tx_id INTEGER PRIMARY KEY, state INTEGER NOT NULL, client INTEGER NOT NULL,
+                                                 amount INTEGER NOT NULL, created INTEGER NOT NULL);";
+
+/// Changes written to the store file at once. The journal undoes them if the run is aborted
+const DISK_BATCH_SIZE: usize = 10_000;
+
+
+/**
+ * Lifecycle of a stored deposit:
+ *    Normal -> Disputed -> Resolved
+ *                       -> ChargedBack
+ * Only a deposit in Normal state can be disputed, so double disputes and
+ * disputes of charged back deposits are rejected
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionState {
+    Normal,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/**
+ * Deposit kept for later reference by dispute, resolve and chargeback
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredDeposit {
+    #[serde(rename = "client")]
+    pub client_id:     u16,
+    pub amount:        Amount,
+    pub state:         TransactionState,
+}
+
+/**
+ * What a store knows about a used transaction id
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoredTransaction {
+    // Deposit that can be referenced by disputes
+    Deposit(StoredDeposit),
+    // Withdrawal, or deposit that is no longer kept. Only its id is known
+    IdOnly,
+}
+
+/**
+ * Contents of a store, so they can be saved in a state file and loaded in another store
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreState {
+    // Used ids, as ranges of consecutive ids: first and last
+    pub ids:           Vec<(u32, u32)>,
+    // Kept deposits, oldest first
+    pub deposits:      Vec<(u32, StoredDeposit)>,
+}
+
+/**
+ * Storage of the transactions that later ones can reference. Every deposit and withdrawal
+ * id is kept, so ids can not be reused. Only deposits can be disputed, so only they keep
+ * their client, amount and state
+ */
+pub trait TransactionStore: fmt::Debug + Send {
+    /**
+     * Register the id of a deposit or withdrawal. It returns false if it was already used
+     */
+    fn add_id(&mut self, in_tx_id: u32) -> io::Result<bool>;
+
+    /**
+     * Keep a new deposit, or update the state of a kept one. Its id shall have been registered
+     */
+    fn put_deposit(&mut self, in_tx_id: u32, in_deposit: StoredDeposit) -> io::Result<()>;
+
+    /**
+     * Search a transaction id. None if it has not been used
+     */
+    fn get(&mut self, in_tx_id: u32) -> io::Result<Option<StoredTransaction>>;
+
+    /**
+     * Make sure the changes are saved
+     */
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /**
+     * True if the store keeps its contents in its own file, so they do not need to be
+     * saved in a state file
+     */
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    /**
+     * Generation of a persistent store once the current changes are committed, so a state
+     * file can be matched with it. None if the store is not persistent
+     */
+    fn generation(&self) -> Option<u32> {
+        None
+    }
+
+    /**
+     * Undo the changes of a previous run that were not committed, and check that the store
+     * matches the state file of the given generation. Changes that were saved in that state
+     * file, but not committed, are kept. Without state file, the store shall be new
+     */
+    fn recover(&mut self, _in_generation: Option<u32>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /**
+     * Make the changes permanent. It shall be called after they are flushed and the state
+     * file is saved
+     */
+    fn commit(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /**
+     * Contents of the store. Stores that are not persistent shall implement it
+     */
+    fn export(&mut self) -> io::Result<StoreState> {
+        Err( io::Error::new(io::ErrorKind::Unsupported, "the transaction store can not be exported") )
+    }
+
+    /**
+     * Add the contents of another store
+     */
+    fn import(&mut self, in_state: &StoreState) -> io::Result<()> {
+        for &(first, last) in &in_state.ids {
+            for tx_id in first..=last {
+                self.add_id(tx_id)?;
+            }
+        }
+        for &(tx_id, deposit) in &in_state.deposits {
+            self.put_deposit(tx_id, deposit)?;
+        }
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Set of transaction ids. Ids are usually consecutive, so they are kept as bitmaps of
+ * 8 KB per 65536 ids. The whole range of ids takes 512 MB at most
+ */
+#[derive(Debug, Clone, Default)]
+pub struct TxIdSet {
+    pages:         HashMap<u32, Box<[u64; PAGE_WORDS]>>,
+}
+
+impl TxIdSet {
+    pub fn new() -> Self {
+        TxIdSet::default()
+    }
+
+    fn position(in_tx_id: u32) -> (u32, usize, u64) {
+        let page = in_tx_id >> PAGE_BITS;
+        let bit  = (in_tx_id & ((1 << PAGE_BITS) - 1)) as usize;
+        (page, bit / 64, 1 << (bit % 64))
+    }
+
+    pub fn contains(&self, in_tx_id: u32) -> bool {
+        let (page, word, mask) = TxIdSet::position(in_tx_id);
+        self.pages.get(&page).is_some_and(|p| p[word] & mask != 0)
+    }
+
+    /**
+     * Add an id. It returns false if it was already in the set
+     */
+    pub fn insert(&mut self, in_tx_id: u32) -> bool {
+        let (page, word, mask) = TxIdSet::position(in_tx_id);
+        let bits = self.pages.entry(page).or_insert_with(|| Box::new([0; PAGE_WORDS]));
+
+        let is_new = bits[word] & mask == 0;
+        bits[word] |= mask;
+        is_new
+    }
+
+    /**
+     * Ids in the set as ranges of consecutive ids, in order
+     */
+    fn ranges(&self) -> Vec<(u32, u32)> {
+        let mut page_ids: Vec<u32> = self.pages.keys().copied().collect();
+        page_ids.sort_unstable();
+
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for page_id in page_ids {
+            let bits = &self.pages[&page_id];
+            for (word_index, &word) in bits.iter().enumerate() {
+                if word == 0 {
+                    continue;
+                }
+                for bit in 0..64 {
+                    if word & (1 << bit) == 0 {
+                        continue;
+                    }
+                    let tx_id = (page_id << PAGE_BITS) | (word_index * 64 + bit) as u32;
+                    match ranges.last_mut() {
+                        Some(last) if last.1.checked_add(1) == Some(tx_id) => last.1 = tx_id,
+                        _                                                => ranges.push((tx_id, tx_id)),
+                    }
+                }
+            }
+        }
+        ranges
+    }
+}
+
+/**
+ * Store in memory. Memory grows with the number of deposits, not with the number of rows.
+ *
+ * If there is a limit of deposits, the oldest ones are dropped when it is exceeded and
+ * they can not be disputed anymore. Deposits under dispute are never dropped
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    tx_ids:        TxIdSet,
+    deposits:      HashMap<u32, StoredDeposit>,
+    // Deposits that can be dropped, in arrival order. Only kept if there is a limit.
+    // Disputed deposits are queued again when resolved or charged back
+    order:         VecDeque<u32>,
+    // Entries of the order left behind by disputed deposits, per id. They are skipped
+    stale:         HashMap<u32, u32>,
+    limit:         Option<usize>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /**
+     * Keep at most the given number of deposits
+     */
+    pub fn with_limit(in_limit: usize) -> Self {
+        MemoryStore {
+            limit:  Some(in_limit),
+            ..MemoryStore::default()
+        }
+    }
+
+    /**
+     * Drop the oldest deposits until the limit is met. Deposits under dispute are kept,
+     * as they still have to be resolved or charged back, so they leave the order
+     */
+    fn evict(&mut self) {
+        let limit = match self.limit {
+            Some(l) => l,
+            None    => return,
+        };
+
+        while self.deposits.len() > limit {
+            let tx_id = match self.order.pop_front() {
+                Some(id) => id,
+                None     => break,
+            };
+
+            if !take_stale(&mut self.stale, tx_id) {
+                self.deposits.remove(&tx_id);
+            }
+        }
+    }
+}
+
+/**
+ * Skip an entry of the order left behind by a disputed deposit. It returns false if the
+ * entry is the current one of the deposit
+ */
+fn take_stale(io_stale: &mut HashMap<u32, u32>, in_tx_id: u32) -> bool {
+    match io_stale.get_mut(&in_tx_id) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 0 {
+                io_stale.remove(&in_tx_id);
+            }
+            true
+        },
+        None => false,
+    }
+}
+
+impl TransactionStore for MemoryStore {
+    fn add_id(&mut self, in_tx_id: u32) -> io::Result<bool> {
+        Ok(self.tx_ids.insert(in_tx_id))
+    }
+
+    fn put_deposit(&mut self, in_tx_id: u32, in_deposit: StoredDeposit) -> io::Result<()> {
+        let previous = self.deposits.insert(in_tx_id, in_deposit);
+        if self.limit.is_none() {
+            return Ok(());
+        }
+
+        // New deposits, and disputed ones that are settled, can be dropped again. The entry
+        // of a deposit that is disputed stays in the order, but it is no longer valid
+        let was_disputed = previous.is_some_and(|d| d.state == TransactionState::Disputed);
+        let is_disputed = in_deposit.state == TransactionState::Disputed;
+        if (previous.is_none() || was_disputed) && !is_disputed {
+            self.order.push_back(in_tx_id);
+        }
+        if previous.is_some() && !was_disputed && is_disputed {
+            *self.stale.entry(in_tx_id).or_insert(0) += 1;
+        }
+        if previous.is_none() {
+            self.evict();
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, in_tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        let found = match self.deposits.get(&in_tx_id) {
+            Some(d)                                => Some(StoredTransaction::Deposit(*d)),
+            None if self.tx_ids.contains(in_tx_id) => Some(StoredTransaction::IdOnly),
+            None                                   => None,
+        };
+        Ok(found)
+    }
+
+    fn export(&mut self) -> io::Result<StoreState> {
+        // Deposits in arrival order, if it is known, so the oldest are still dropped first.
+        // Disputed deposits are not in the order, they go last
+        let deposits = if self.limit.is_some() {
+            let mut stale = self.stale.clone();
+            let mut deposits: Vec<(u32, StoredDeposit)> = self.order.iter()
+                                                                  .filter(|id| !take_stale(&mut stale, **id))
+                                                                  .map(|id| (*id, self.deposits[id]))
+                                                                  .collect();
+            let mut disputed: Vec<(u32, StoredDeposit)> = self.deposits.iter()
+                                                                       .filter(|(_, d)| d.state == TransactionState::Disputed)
+                                                                       .map(|(id, d)| (*id, *d))
+                                                                       .collect();
+            disputed.sort_unstable_by_key(|(id, _)| *id);
+            deposits.extend(disputed);
+            deposits
+        } else {
+            let mut deposits: Vec<(u32, StoredDeposit)> = self.deposits.iter().map(|(id, d)| (*id, *d)).collect();
+            deposits.sort_unstable_by_key(|(id, _)| *id);
+            deposits
+        };
+
+        Ok(StoreState {
+            ids:  self.tx_ids.ranges(),
+            deposits,
+        })
+    }
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Store in a SQLite file, so long ledgers do not need memory for their transactions. The
+ * file keeps its contents between runs. Table 'transactions' has a row per used id:
+ *    state    - 1 id only, 2 normal, 3 disputed, 4 resolved, 5 charged back
+ *    client   - client id of a deposit
+ *    amount   - amount of a deposit in 1/10000 units
+ *    created  - generation that added the id
+ *
+ * The store keeps a generation, increased by the first change of every run and saved in
+ * the state file. Until a run commits, the first previous contents of every row it changes
+ * are kept in table 'journal', and rows it adds are known by their generation, so the run
+ * is undone by the next one if the state file was not saved
+ */
+#[derive(Debug)]
+pub struct DiskStore {
+    connection:    Connection,
+    generation:    u32,
+    // Generation before the changes of a run that has not committed yet
+    base:          Option<u32>,
+    // Changes written in the current SQLite transaction
+    batch_size:    usize,
+    // The changes of a previous run have been handled
+    recovered:     bool,
+}
+
+impl DiskStore {
+    /**
+     * Open a store file, or create it if it does not exist
+     */
+    pub fn open<P: AsRef<Path>>(in_path: P) -> io::Result<Self> {
+        let mut connection = Connection::open(in_path).map_err(sql_error)?;
+        connection.pragma_update(None, "synchronous", "FULL").map_err(sql_error)?;
+
+        let application_id: i32 = connection.pragma_query_value(None, "application_id", |r| r.get(0)).map_err(sql_error)?;
+        let table_count: i64 = connection.query_row("SELECT count(*) FROM sqlite_master", [], |r| r.get(0)).map_err(sql_error)?;
+
+        if application_id == 0 && table_count == 0 {
+            let transaction = connection.transaction().map_err(sql_error)?;
+            transaction.execute_batch(DISK_SCHEMA).map_err(sql_error)?;
+            transaction.execute("INSERT INTO store VALUES (?1, 0, NULL)", [DISK_VERSION]).map_err(sql_error)?;
+            transaction.pragma_update(None, "application_id", DISK_APPLICATION_ID).map_err(sql_error)?;
+            transaction.commit().map_err(sql_error)?;
+        } else if application_id != DISK_APPLICATION_ID {
+            return Err( io::Error::new(io::ErrorKind::InvalidData, "it is not a transaction store") );
+        }
+
+        let (version, generation, base): (u32, u32, Option<u32>) = connection.query_row("SELECT version, generation, base FROM store", [], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                                                                             .map_err(sql_error)?;
+        if version != DISK_VERSION {
+            return Err( io::Error::new(io::ErrorKind::InvalidData, "unsupported transaction store version") );
+        }
+
+        Ok(DiskStore {
+            connection,
+            generation,
+            base,
+            batch_size:  0,
+            recovered:   false,
+        })
+    }
+
+    /**
+     * Handle the changes of a previous run that did not commit. They are kept if the state
+     * file of their generation was saved, otherwise they are undone
+     */
+    fn finish_previous_run(&mut self, in_generation: Option<u32>) -> io::Result<()> {
+        self.recovered = true;
+
+        let base = match self.base {
+            Some(b) => b,
+            None    => return Ok(()),
+        };
+        if in_generation == Some(self.generation) {
+            return self.commit();
+        }
+
+        // Rows added by the run are dropped, rows changed are restored
+        let transaction = self.connection.transaction().map_err(sql_error)?;
+        transaction.execute("DELETE FROM transactions WHERE created > ?1", [base]).map_err(sql_error)?;
+        transaction.execute_batch("INSERT OR REPLACE INTO transactions SELECT * FROM journal;
+                                   DELETE FROM journal;").map_err(sql_error)?;
+        transaction.execute("UPDATE store SET generation = ?1, base = NULL", [base]).map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)?;
+
+        self.generation = base;
+        self.base = None;
+        Ok(())
+    }
+
+    /**
+     * Prepare a change: the previous run is handled, the changes are grouped in SQLite
+     * transactions, and the first change of a run starts a new generation
+     */
+    fn start_change(&mut self) -> io::Result<()> {
+        if !self.recovered {
+            self.finish_previous_run(None)?;
+        }
+
+        if self.batch_size >= DISK_BATCH_SIZE {
+            self.connection.execute_batch("COMMIT").map_err(sql_error)?;
+            self.batch_size = 0;
+        }
+        if self.batch_size == 0 {
+            self.connection.execute_batch("BEGIN").map_err(sql_error)?;
+        }
+        self.batch_size += 1;
+
+        if self.base.is_none() {
+            self.connection.execute("UPDATE store SET base = generation, generation = generation + 1", []).map_err(sql_error)?;
+            self.base = Some(self.generation);
+            self.generation = self.generation.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn add_id(&mut self, in_tx_id: u32) -> io::Result<bool> {
+        self.start_change()?;
+
+        let added = self.connection.prepare_cached("INSERT OR IGNORE INTO transactions (tx_id, state, client, amount, created) VALUES (?1, 1, 0, 0, ?2)")
+                                   .and_then(|mut s| s.execute([in_tx_id, self.generation]))
+                                   .map_err(sql_error)?;
+        Ok(added == 1)
+    }
+
+    fn put_deposit(&mut self, in_tx_id: u32, in_deposit: StoredDeposit) -> io::Result<()> {
+        self.start_change()?;
+
+        let state: u8 = match in_deposit.state {
+            TransactionState::Normal      => 2,
+            TransactionState::Disputed    => 3,
+            TransactionState::Resolved    => 4,
+            TransactionState::ChargedBack => 5,
+        };
+
+        // Only the first previous contents are needed to undo the run
+        self.connection.prepare_cached("INSERT OR IGNORE INTO journal SELECT * FROM transactions WHERE tx_id = ?1 AND created < ?2")
+                       .and_then(|mut s| s.execute([in_tx_id, self.generation]))
+                       .map_err(sql_error)?;
+        self.connection.prepare_cached("INSERT INTO transactions (tx_id, state, client, amount, created) VALUES (?1, ?2, ?3, ?4, ?5)
+                                        ON CONFLICT (tx_id) DO UPDATE SET state = excluded.state, client = excluded.client, amount = excluded.amount")
+                       .and_then(|mut s| s.execute(params![in_tx_id, state, in_deposit.client_id, in_deposit.amount.raw(), self.generation]))
+                       .map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn get(&mut self, in_tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        if !self.recovered {
+            self.finish_previous_run(None)?;
+        }
+
+        let row: Option<(u8, u16, i64)> = self.connection.prepare_cached("SELECT state, client, amount FROM transactions WHERE tx_id = ?1")
+                                                         .and_then(|mut s| s.query_row([in_tx_id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?))).optional())
+                                                         .map_err(sql_error)?;
+        let (state, client_id, amount) = match row {
+            Some(r) => r,
+            None    => return Ok(None),
+        };
+
+        let state = match state {
+            1 => return Ok(Some(StoredTransaction::IdOnly)),
+            2 => TransactionState::Normal,
+            3 => TransactionState::Disputed,
+            4 => TransactionState::Resolved,
+            5 => TransactionState::ChargedBack,
+            _ => return Err( io::Error::new(io::ErrorKind::InvalidData, format!("invalid record of transaction: {}", in_tx_id)) ),
+        };
+
+        Ok(Some(StoredTransaction::Deposit(StoredDeposit {
+            client_id,
+            amount:     Amount::from_raw(amount),
+            state,
+        })))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.batch_size > 0 {
+            self.connection.execute_batch("COMMIT").map_err(sql_error)?;
+            self.batch_size = 0;
+        }
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn generation(&self) -> Option<u32> {
+        Some(self.generation)
+    }
+
+    fn recover(&mut self, in_generation: Option<u32>) -> io::Result<()> {
+        if !self.recovered {
+            self.finish_previous_run(in_generation)?;
+        }
+
+        match in_generation {
+            Some(g) if g != self.generation => Err( io::Error::new(io::ErrorKind::InvalidData,
+                                                                   format!("the transaction store does not match the state file. Store generation: {}, state generation: {}", self.generation, g)) ),
+            None if self.generation != 0    => Err( io::Error::new(io::ErrorKind::InvalidData,
+                                                                   "the transaction store keeps the transactions of other runs, but there is no state file") ),
+            _                               => Ok(()),
+        }
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        if self.base.is_none() {
+            return Ok(());
+        }
+        self.flush()?;
+
+        self.connection.execute_batch("BEGIN;
+                                       DELETE FROM journal;
+                                       UPDATE store SET base = NULL;
+                                       COMMIT;").map_err(sql_error)?;
+        self.base = None;
+        Ok(())
+    }
+}
+
+fn sql_error(in_error: rusqlite::Error) -> io::Error {
+    io::Error::other(in_error)
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Store that records the changes made to another one, so they can be saved little by
+ * little, i.e. in checkpoints, instead of exporting the whole store every time
+ */
+#[derive(Debug)]
+pub struct RecordingStore {
+    inner:         Box<dyn TransactionStore>,
+    changes:       StoreChanges,
+}
+
+/**
+ * Changes recorded by a `RecordingStore`. They can be taken while an engine uses the store
+ */
+#[derive(Debug, Clone, Default)]
+pub struct StoreChanges {
+    changes:       Arc<Mutex<StoreState>>,
+}
+
+impl RecordingStore {
+    pub fn new(in_store: Box<dyn TransactionStore>) -> Self {
+        RecordingStore {
+            inner:    in_store,
+            changes:  StoreChanges::default(),
+        }
+    }
+
+    /**
+     * Handle to the changes recorded from now on
+     */
+    pub fn changes(&self) -> StoreChanges {
+        self.changes.clone()
+    }
+}
+
+impl StoreChanges {
+    /**
+     * Changes recorded since the last call. Importing them in order rebuilds the store
+     */
+    pub fn take(&self) -> StoreState {
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::take(&mut *changes)
+    }
+
+    fn record<F: FnOnce(&mut StoreState)>(&self, in_change: F) {
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        in_change(&mut changes);
+    }
+}
+
+impl TransactionStore for RecordingStore {
+    fn add_id(&mut self, in_tx_id: u32) -> io::Result<bool> {
+        let is_new = self.inner.add_id(in_tx_id)?;
+        if is_new {
+            self.changes.record(|c| match c.ids.last_mut() {
+                Some((_, last)) if last.checked_add(1) == Some(in_tx_id) => *last = in_tx_id,
+                _                                                       => c.ids.push((in_tx_id, in_tx_id)),
+            });
+        }
+        Ok(is_new)
+    }
+
+    fn put_deposit(&mut self, in_tx_id: u32, in_deposit: StoredDeposit) -> io::Result<()> {
+        self.inner.put_deposit(in_tx_id, in_deposit)?;
+        self.changes.record(|c| c.deposits.push((in_tx_id, in_deposit)));
+        Ok(())
+    }
+
+    fn get(&mut self, in_tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        self.inner.get(in_tx_id)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.inner.is_persistent()
+    }
+
+    fn generation(&self) -> Option<u32> {
+        self.inner.generation()
+    }
+
+    fn recover(&mut self, in_generation: Option<u32>) -> io::Result<()> {
+        self.inner.recover(in_generation)
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.inner.commit()
+    }
+
+    fn export(&mut self) -> io::Result<StoreState> {
+        self.inner.export()
+    }
+}
+
+// ---------------------------------------------------------------------
+
+/**
+ * Store shared by several engines, i.e. one per thread, so transaction ids are unique
+ * across all of them. Each operation locks the store
+ */
+#[derive(Debug, Clone)]
+pub struct SharedStore {
+    inner:         Arc<Mutex<Box<dyn TransactionStore>>>,
+}
+
+impl SharedStore {
+    pub fn new(in_store: Box<dyn TransactionStore>) -> Self {
+        SharedStore {
+            inner:  Arc::new(Mutex::new(in_store)),
+        }
+    }
+
+    /**
+     * Get the store back. It fails, returning itself, if it is still shared
+     */
+    pub fn into_inner(self) -> Result<Box<dyn TransactionStore>, SharedStore> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(m)      => Ok( m.into_inner().unwrap_or_else(|e| e.into_inner()) ),
+            Err(inner) => Err( SharedStore { inner } ),
+        }
+    }
+
+    fn with_store<T, F>(&self, in_operation: F) -> io::Result<T>
+        where F: FnOnce(&mut dyn TransactionStore) -> io::Result<T> {
+        let mut store = self.inner.lock()
+                                  .map_err(|_| io::Error::other("the transaction store was left in an invalid state"))?;
+        in_operation(store.as_mut())
+    }
+}
+
+impl TransactionStore for SharedStore {
+    fn add_id(&mut self, in_tx_id: u32) -> io::Result<bool> {
+        self.with_store(|s| s.add_id(in_tx_id))
+    }
+
+    fn put_deposit(&mut self, in_tx_id: u32, in_deposit: StoredDeposit) -> io::Result<()> {
+        self.with_store(|s| s.put_deposit(in_tx_id, in_deposit))
+    }
+
+    fn get(&mut self, in_tx_id: u32) -> io::Result<Option<StoredTransaction>> {
+        self.with_store(|s| s.get(in_tx_id))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_store(|s| s.flush())
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.with_store(|s| Ok(s.is_persistent())).unwrap_or(false)
+    }
+
+    fn generation(&self) -> Option<u32> {
+        self.with_store(|s| Ok(s.generation())).unwrap_or(None)
+    }
+
+    fn recover(&mut self, in_generation: Option<u32>) -> io::Result<()> {
+        self.with_store(|s| s.recover(in_generation))
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.with_store(|s| s.commit())
+    }
+
+    fn export(&mut self) -> io::Result<StoreState> {
+        self.with_store(|s| s.export())
+    }
+
+    fn import(&mut self, in_state: &StoreState) -> io::Result<()> {
+        self.with_store(|s| s.import(in_state))
+    }
+}
+
+// ---------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn deposit(in_client_id: u16, in_amount: i64, in_state: TransactionState) -> StoredDeposit {
+        StoredDeposit {
+            client_id:  in_client_id,
+            amount:     Amount::from_raw(in_amount),
+            state:      in_state,
+        }
+    }
+
+    /**
+     * Add new deposits of client 1, as the engine does
+     */
+    fn add_deposits(io_store: &mut dyn TransactionStore, in_tx_ids: &[u32]) {
+        for &tx_id in in_tx_ids {
+            assert!(io_store.add_id(tx_id).unwrap());
+            io_store.put_deposit(tx_id, deposit(1, i64::from(tx_id) * 10_000, TransactionState::Normal)).unwrap();
+        }
+    }
+
+    fn set_state(io_store: &mut dyn TransactionStore, in_tx_id: u32, in_state: TransactionState) {
+        io_store.put_deposit(in_tx_id, deposit(1, i64::from(in_tx_id) * 10_000, in_state)).unwrap();
+    }
+
+    /**
+     * Ids of the deposits still kept by a store, among the given ones
+     */
+    fn kept(io_store: &mut dyn TransactionStore, in_tx_ids: std::ops::RangeInclusive<u32>) -> Vec<u32> {
+        in_tx_ids.filter(|id| matches!(io_store.get(*id).unwrap(), Some(StoredTransaction::Deposit(_))))
+                 .collect()
+    }
+
+    #[test]
+    fn memory_store_limit() {
+        let mut store = MemoryStore::with_limit(3);
+        add_deposits(&mut store, &[1, 2, 3, 4, 5]);
+
+        assert_eq!(kept(&mut store, 1..=5), vec![3, 4, 5]);
+        // The ids of dropped deposits are still used
+        assert_eq!(store.get(1).unwrap(), Some(StoredTransaction::IdOnly));
+        assert!(!store.add_id(1).unwrap());
+    }
+
+    #[test]
+    fn memory_store_keeps_disputed_deposits() {
+        let mut store = MemoryStore::with_limit(2);
+        add_deposits(&mut store, &[1, 2]);
+        set_state(&mut store, 1, TransactionState::Disputed);
+        add_deposits(&mut store, &[3, 4, 5]);
+
+        assert_eq!(kept(&mut store, 1..=5), vec![1, 5]);
+        assert_eq!(store.get(1).unwrap(), Some(StoredTransaction::Deposit(deposit(1, 10_000, TransactionState::Disputed))));
+
+        // Only disputed deposits over the limit
+        set_state(&mut store, 5, TransactionState::Disputed);
+        add_deposits(&mut store, &[6]);
+        assert_eq!(kept(&mut store, 1..=6), vec![1, 5]);
+    }
+
+    #[test]
+    fn memory_store_queues_settled_deposits_again() {
+        for settled in [TransactionState::Resolved, TransactionState::ChargedBack].iter() {
+            let mut store = MemoryStore::with_limit(3);
+            add_deposits(&mut store, &[1, 2, 3]);
+            set_state(&mut store, 1, TransactionState::Disputed);
+            set_state(&mut store, 1, *settled);
+
+            // Deposit 1 is now the newest one
+            add_deposits(&mut store, &[4]);
+            assert_eq!(kept(&mut store, 1..=4), vec![1, 3, 4], "Settled: {:?}", settled);
+            add_deposits(&mut store, &[5, 6]);
+            assert_eq!(kept(&mut store, 1..=6), vec![4, 5, 6], "Settled: {:?}", settled);
+        }
+    }
+
+    #[test]
+    fn memory_store_export_keeps_the_order() {
+        let mut store = MemoryStore::with_limit(4);
+        add_deposits(&mut store, &[1, 2, 3, 4]);
+        set_state(&mut store, 1, TransactionState::Disputed);
+        set_state(&mut store, 2, TransactionState::Disputed);
+        set_state(&mut store, 1, TransactionState::Resolved);
+        assert!(store.add_id(5).unwrap());
+
+        let state = store.export().unwrap();
+        assert_eq!(state.ids, vec![(1, 5)]);
+        assert_eq!(state.deposits.iter().map(|(id, _)| *id).collect::<Vec<u32>>(), vec![3, 4, 1, 2]);
+
+        let mut imported = MemoryStore::with_limit(4);
+        imported.import(&state).unwrap();
+        assert_eq!(imported.export().unwrap(), state);
+
+        // Both drop the same deposits from now on
+        for tx_id in 6..=9 {
+            add_deposits(&mut store, &[tx_id]);
+            add_deposits(&mut imported, &[tx_id]);
+            assert_eq!(kept(&mut imported, 1..=9), kept(&mut store, 1..=9), "After deposit: {}", tx_id);
+        }
+        assert_eq!(kept(&mut store, 1..=9), vec![2, 7, 8, 9]);
+    }
+
+    /**
+     * Path of a new store file for a test
+     */
+    fn store_path(in_name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("store_test_{}_{}", process::id(), in_name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn journal_size(in_store: &DiskStore) -> i64 {
+        in_store.connection.query_row("SELECT count(*) FROM journal", [], |r| r.get(0)).expect("Counting journal")
+    }
+
+    /**
+     * Store of generation 1 with deposit 1 of client 1 and withdrawal 2
+     */
+    fn first_run(in_path: &Path) {
+        let mut store = DiskStore::open(in_path).expect("Opening store");
+        store.recover(None).expect("New store");
+        assert!(store.add_id(1).unwrap());
+        store.put_deposit(1, deposit(1, 50_000, TransactionState::Normal)).unwrap();
+        assert!(store.add_id(2).unwrap());
+        store.flush().unwrap();
+        assert_eq!(store.generation(), Some(1));
+        store.commit().unwrap();
+    }
+
+    /**
+     * Changes of a second run, flushed but not committed, as if the run stopped while
+     * saving the state file
+     */
+    fn second_run(in_path: &Path) {
+        let mut store = DiskStore::open(in_path).expect("Opening store");
+        store.recover(Some(1)).expect("Matching store");
+        assert!(store.add_id(3).unwrap());
+        store.put_deposit(3, deposit(2, 10_000, TransactionState::Normal)).unwrap();
+        store.put_deposit(1, deposit(1, 50_000, TransactionState::Disputed)).unwrap();
+        store.put_deposit(1, deposit(1, 50_000, TransactionState::Resolved)).unwrap();
+        assert!(!store.add_id(2).unwrap());
+        store.flush().unwrap();
+        assert_eq!(store.generation(), Some(2));
+        // Only the first previous contents of the deposit of the first run
+        assert_eq!(journal_size(&store), 1);
+    }
+
+    #[test]
+    fn disk_store_keeps_its_contents() {
+        let path = store_path("contents");
+        first_run(&path);
+
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(1)).expect("Matching store");
+        assert_eq!(store.get(1).unwrap(), Some(StoredTransaction::Deposit(deposit(1, 50_000, TransactionState::Normal))));
+        assert_eq!(store.get(2).unwrap(), Some(StoredTransaction::IdOnly));
+        assert_eq!(store.get(3).unwrap(), None);
+
+        // Ids are keys, not positions in the file
+        assert!(store.add_id(u32::MAX).unwrap());
+        store.flush().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < 1024 * 1024);
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_checks_the_generation() {
+        let path = store_path("generation");
+        first_run(&path);
+
+        for generation in [None, Some(0), Some(2)].iter() {
+            let mut store = DiskStore::open(&path).expect("Opening store");
+            assert!(store.recover(*generation).is_err(), "Generation: {:?}", generation);
+        }
+
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(1)).expect("Matching store");
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_undoes_a_run_without_state() {
+        let path = store_path("undo");
+        first_run(&path);
+        second_run(&path);
+
+        // The state file of the second run was not saved
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(1)).expect("Undoing the second run");
+        assert_eq!(store.generation(), Some(1));
+        assert_eq!(store.get(1).unwrap(), Some(StoredTransaction::Deposit(deposit(1, 50_000, TransactionState::Normal))));
+        assert_eq!(store.get(3).unwrap(), None);
+        assert_eq!(journal_size(&store), 0);
+        drop(store);
+
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        assert!(store.recover(Some(2)).is_err());
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_keeps_a_run_with_state() {
+        let path = store_path("keep");
+        first_run(&path);
+        second_run(&path);
+
+        // The state file of the second run was saved, but the store was not committed
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(2)).expect("Keeping the second run");
+        assert_eq!(store.get(1).unwrap(), Some(StoredTransaction::Deposit(deposit(1, 50_000, TransactionState::Resolved))));
+        assert_eq!(store.get(3).unwrap(), Some(StoredTransaction::Deposit(deposit(2, 10_000, TransactionState::Normal))));
+        assert_eq!(journal_size(&store), 0);
+        drop(store);
+
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(2)).expect("Committed store");
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_undoes_a_run_stopped_between_writes() {
+        let path = store_path("stopped");
+        first_run(&path);
+
+        // More changes than a write, so some of them are in the file and the last ones are not
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(1)).expect("Matching store");
+        store.put_deposit(1, deposit(1, 50_000, TransactionState::Disputed)).unwrap();
+        for tx_id in 10..(10 + DISK_BATCH_SIZE as u32 * 3 / 2) {
+            assert!(store.add_id(tx_id).unwrap());
+        }
+        drop(store);
+
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        assert_eq!(store.base, Some(1));
+        store.recover(Some(1)).expect("Undoing the stopped run");
+        assert_eq!(store.get(1).unwrap(), Some(StoredTransaction::Deposit(deposit(1, 50_000, TransactionState::Normal))));
+        assert_eq!(store.get(10).unwrap(), None);
+        assert_eq!(store.get(2).unwrap(), Some(StoredTransaction::IdOnly));
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn commit_drops_the_journal() {
+        let path = store_path("commit");
+        first_run(&path);
+
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(1)).expect("Matching store");
+        store.put_deposit(1, deposit(1, 50_000, TransactionState::Disputed)).unwrap();
+        store.flush().unwrap();
+        assert_eq!(journal_size(&store), 1);
+
+        store.commit().unwrap();
+        assert_eq!(journal_size(&store), 0);
+        assert_eq!(store.generation(), Some(2));
+
+        // The next run starts a new generation
+        store.put_deposit(1, deposit(1, 50_000, TransactionState::Resolved)).unwrap();
+        assert_eq!(store.generation(), Some(3));
+        store.commit().unwrap();
+        drop(store);
+
+        let mut store = DiskStore::open(&path).expect("Opening store");
+        store.recover(Some(3)).expect("Committed store");
+        assert_eq!(store.get(1).unwrap(), Some(StoredTransaction::Deposit(deposit(1, 50_000, TransactionState::Resolved))));
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_rejects_other_files() {
+        let path = store_path("other");
+        fs::write(&path, "type,client,tx,amount\n").unwrap();
+        assert!(DiskStore::open(&path).and_then(|mut s| s.get(1)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ *  Input transaction and client account
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use csv::ByteRecord;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::amount::Amount;
+
+
+/**
+ * Type of transaction. Names are case insensitive
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Unlock,
+}
+
+impl TransactionKind {
+    pub const ALL: [TransactionKind; 6] = [
+        TransactionKind::Deposit,
+        TransactionKind::Withdrawal,
+        TransactionKind::Dispute,
+        TransactionKind::Resolve,
+        TransactionKind::Chargeback,
+        TransactionKind::Unlock,
+    ];
+
+    /**
+     * Name used in the input and output files
+     */
+    pub fn name(self) -> &'static str {
+        match self {
+            TransactionKind::Deposit    => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+            TransactionKind::Dispute    => "dispute",
+            TransactionKind::Resolve    => "resolve",
+            TransactionKind::Chargeback => "chargeback",
+            TransactionKind::Unlock     => "unlock",
+        }
+    }
+}
+
+impl fmt::Display for TransactionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/**
+ * Error returned when the type of a transaction is not known. It keeps the offending value
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownKind(pub String);
+
+impl fmt::Display for UnknownKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown transaction type: '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownKind {}
+
+impl FromStr for TransactionKind {
+    type Err = UnknownKind;
+
+    fn from_str(in_text: &str) -> Result<Self, Self::Err> {
+        let text = in_text.trim();
+        TransactionKind::ALL.iter()
+                            .find(|k| k.name().eq_ignore_ascii_case(text))
+                            .copied()
+                            .ok_or_else(|| UnknownKind(text.to_string()))
+    }
+}
+
+impl Serialize for TransactionKind {
+    fn serialize<S: Serializer>(&self, in_serializer: S) -> Result<S::Ok, S::Error> {
+        in_serializer.serialize_str(self.name())
+    }
+}
+
+struct TransactionKindVisitor;
+
+impl<'de> Visitor<'de> for TransactionKindVisitor {
+    type Value = TransactionKind;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a transaction type")
+    }
+
+    fn visit_str<E: de::Error>(self, in_value: &str) -> Result<TransactionKind, E> {
+        in_value.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionKind {
+    fn deserialize<D: Deserializer<'de>>(in_deserializer: D) -> Result<Self, D::Error> {
+        in_deserializer.deserialize_str(TransactionKindVisitor)
+    }
+}
+
+/**
+ * Alternative names for the transaction types, i.e. "credit" for deposit. 
+ * They are case insensitive, like the standard names
+ */
+#[derive(Debug, Clone, Default)]
+pub struct KindAliases {
+    aliases:       HashMap<String, TransactionKind>,
+}
+
+impl KindAliases {
+    pub fn new() -> Self {
+        KindAliases::default()
+    }
+
+    pub fn add(&mut self, in_alias: &str, in_kind: TransactionKind) {
+        self.aliases.insert(in_alias.trim().to_ascii_lowercase(), in_kind);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /**
+     * Type of a transaction given its standard name or one of its aliases
+     */
+    pub fn resolve(&self, in_text: &str) -> Result<TransactionKind, UnknownKind> {
+        in_text.parse().or_else(|e| {
+            self.aliases.get(&in_text.trim().to_ascii_lowercase())
+                        .copied()
+                        .ok_or(e)
+        })
+    }
+}
+
+/**
+ * Input transaction, as read from one row of the input file
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub kind:      TransactionKind,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    #[serde(rename = "tx")]
+    pub tx_id:     u32,
+    // Only deposit and withdrawal carry an amount. It is ignored in the rest
+    #[serde(default)]
+    pub amount:    Option<Amount>,
+}
+
+/**
+ * Operation received while its account is locked. It keeps the input and line it was
+ * read from, and the row as it was read with the columns of its input, so it can be
+ * reported when it is applied later
+ */
+#[derive(Debug, Clone)]
+pub struct QueuedTransaction {
+    pub tx:        Transaction,
+    pub source:    String,
+    pub line:      u64,
+    pub columns:   ByteRecord,
+    pub record:    ByteRecord,
+}
+
+/**
+ * Balance of a client
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientAccount {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub available: Amount,
+    pub held:      Amount,
+    pub total:     Amount,
+    pub locked:    bool,
+    // Operations received while the account is locked, when they are queued
+    #[serde(skip)]
+    pub(crate) pending: Vec<QueuedTransaction>,
+}
+
+impl ClientAccount {
+    pub fn new(in_client_id: u16) -> Self {
+        ClientAccount {
+            client_id:  in_client_id,
+            available:  Amount::ZERO,
+            held:       Amount::ZERO,
+            total:      Amount::ZERO,
+            locked:     false,
+            pending:    Vec::new(),
+        }
+    }
+
+    /**
+     * Operations queued while the account is locked, in arrival order
+     */
+    pub fn pending(&self) -> &[QueuedTransaction] {
+        &self.pending
+    }
+}
@@ -0,0 +1,138 @@
+/*
+ *  Tests of checkpoints. A run is aborted after a few checkpoints and resumed, and its
+ *  balances and rejects file are compared with the ones of a run without interruption.
+ *  The same ledger is written as CSV, NDJSON, a JSON array and UTF-16 CSV
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command, Output};
+
+
+const LEDGER_ROWS: u32 = 240;
+
+
+/**
+ * Row of the test ledger: type, client, tx and amount. Some of them are rejected
+ */
+fn ledger_row(in_row: u32) -> (&'static str, String, u32, Option<String>) {
+    let client = (in_row % 7 + 1).to_string();
+
+    match in_row % 10 {
+        _ if in_row % 40 == 3 => ("deposit", "abc".to_string(), in_row, Some("1.0".to_string())),
+        0..=4 => ("deposit", client, in_row, Some(format!("{}.5", in_row))),
+        5..=6 => ("withdrawal", client, in_row, Some(format!("{}.25", in_row % 13 * 20))),
+        7     => ("dispute", client, in_row - 7, None),
+        8     => ("resolve", client, in_row - 8, None),
+        _     => ("deposit", client, in_row - 9, Some("3".to_string())),
+    }
+}
+
+fn csv_ledger() -> String {
+    let mut text = String::from("type,client,tx,amount\n");
+    for row in 1..=LEDGER_ROWS {
+        let (kind, client, tx, amount) = ledger_row(row);
+        text.push_str(&format!("{},{},{},{}\n", kind, client, tx, amount.unwrap_or_default()));
+    }
+    text
+}
+
+fn json_objects() -> Vec<String> {
+    (1..=LEDGER_ROWS).map(|row| {
+                         let (kind, client, tx, amount) = ledger_row(row);
+                         let client = client.parse::<u16>().map_or(format!("\"{}\"", client), |c| c.to_string());
+                         match amount {
+                             Some(a) => format!("{{\"type\":\"{}\",\"client\":{},\"tx\":{},\"amount\":{}}}", kind, client, tx, a),
+                             None    => format!("{{\"type\":\"{}\",\"client\":{},\"tx\":{}}}", kind, client, tx),
+                         }
+                     })
+                     .collect()
+}
+
+fn utf16_ledger() -> Vec<u8> {
+    let mut bytes = vec![0xFF, 0xFE];
+    bytes.extend(csv_ledger().encode_utf16().flat_map(|u| u.to_le_bytes()));
+    bytes
+}
+
+fn temp_file(in_name: &str) -> PathBuf {
+    env::temp_dir().join(format!("checkpoint_{}_{}", process::id(), in_name))
+}
+
+fn run_tool(in_args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_csv_payment"))
+            .args(in_args)
+            .output()
+            .expect("Running the tool")
+}
+
+/**
+ * Process an input in one run, and in a run aborted by --max-errors and then resumed.
+ * Both shall give the same balances and rejects file
+ */
+fn check_resume(in_name: &str, in_content: &[u8], in_format: &str) {
+    let input = temp_file(in_name);
+    let checkpoint = temp_file(&format!("{}.checkpoint", in_name));
+    let full_rejects = temp_file(&format!("{}.full.rejects.csv", in_name));
+    let resumed_rejects = temp_file(&format!("{}.resumed.rejects.csv", in_name));
+    fs::write(&input, in_content).expect("Writing the input");
+
+    let input_arg = input.to_str().unwrap();
+    let checkpoint_arg = checkpoint.to_str().unwrap();
+
+    let full = run_tool(&[input_arg, "--input-format", in_format, "--rejects", full_rejects.to_str().unwrap()]);
+    let full_rejects_text = fs::read_to_string(&full_rejects).unwrap_or_default();
+    assert_eq!(full.status.code(), Some(1), "[{}] {}", in_name, String::from_utf8_lossy(&full.stderr));
+
+    // Stopped after half the rejected rows, so some checkpoints have been written
+    let error_count = full_rejects_text.lines().count() - 1;
+    let max_errors = (error_count / 2).to_string();
+    let options = ["--input-format", in_format, "--rejects", resumed_rejects.to_str().unwrap(), "--checkpoint", checkpoint_arg, "--checkpoint-every", "7"];
+
+    let mut args = vec![input_arg, "--max-errors", &max_errors];
+    args.extend_from_slice(&options);
+    let aborted = run_tool(&args);
+    assert!(!aborted.status.success(), "[{}] Not aborted", in_name);
+    assert!(String::from_utf8_lossy(&aborted.stderr).contains("Processing aborted"), "[{}] {}", in_name, String::from_utf8_lossy(&aborted.stderr));
+    assert!(checkpoint.exists(), "[{}] No checkpoint kept", in_name);
+
+    let mut args = vec![input_arg, "--resume"];
+    args.extend_from_slice(&options);
+    let resumed = run_tool(&args);
+    let resumed_rejects_text = fs::read_to_string(&resumed_rejects).unwrap_or_default();
+
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&full_rejects);
+    let _ = fs::remove_file(&resumed_rejects);
+
+    assert_eq!(resumed.status.code(), Some(1), "[{}] {}", in_name, String::from_utf8_lossy(&resumed.stderr));
+    assert!(!checkpoint.exists(), "[{}] Checkpoint not removed", in_name);
+    assert!(full.stdout.len() > 30, "[{}] No balances", in_name);
+    assert_eq!(String::from_utf8_lossy(&resumed.stdout), String::from_utf8_lossy(&full.stdout), "[{}] Balances", in_name);
+    assert_eq!(resumed_rejects_text, full_rejects_text, "[{}] Rejects file", in_name);
+}
+
+#[test]
+fn resume_csv() {
+    check_resume("ledger.csv", csv_ledger().as_bytes(), "csv");
+}
+
+#[test]
+fn resume_ndjson() {
+    check_resume("ledger.ndjson", (json_objects().join("\n") + "\n").as_bytes(), "ndjson");
+}
+
+#[test]
+fn resume_json_array() {
+    check_resume("ledger.json", format!("[\n{}\n]\n", json_objects().join(",\n")).as_bytes(), "json");
+}
+
+#[test]
+fn resume_utf16() {
+    check_resume("ledger_utf16.csv", &utf16_ledger(), "csv");
+}
@@ -0,0 +1,111 @@
+/*
+ *  Tests of the transaction generator. Files are generated for a few seeds and options,
+ *  processed by the tool, and its balances are compared with the expected ones written
+ *  by the generator
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command, Output};
+
+
+const SEED_LIST: [u64; 5] = [1, 5, 42, 2021, 987_654_321];
+
+
+fn run_tool(in_args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_csv_payment"))
+            .args(in_args)
+            .output()
+            .expect("Running the tool")
+}
+
+fn temp_file(in_name: &str) -> PathBuf {
+    env::temp_dir().join(format!("generator_{}_{}", process::id(), in_name))
+}
+
+/**
+ * Generate a file with the given options, process it and compare the balances with
+ * the expected ones. It returns the differences, if any
+ */
+fn check_generated(in_seed: u64, in_options: &[&str]) -> Result<(), String> {
+    let name = format!("{}_{}", in_seed, in_options.join("_").replace(|c: char| !c.is_ascii_alphanumeric(), ""));
+    let input = temp_file(&format!("{}.csv", name));
+    let expected = temp_file(&format!("{}.expected.csv", name));
+    let seed = in_seed.to_string();
+
+    let mut args = vec!["generate", "--seed", &seed, "--output", input.to_str().unwrap(), "--expected", expected.to_str().unwrap()];
+    args.extend_from_slice(in_options);
+
+    let generated = run_tool(&args);
+    if !generated.status.success() {
+        return Err( format!("[{}] Generating: {}", name, String::from_utf8_lossy(&generated.stderr)) );
+    }
+
+    let processed = run_tool(&[input.to_str().unwrap()]);
+    let expected_text = fs::read_to_string(&expected).unwrap_or_default();
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&expected);
+
+    let actual_text = String::from_utf8_lossy(&processed.stdout);
+    let expected_lines: Vec<&str> = expected_text.lines().collect();
+    let actual_lines: Vec<&str> = actual_text.lines().collect();
+
+    if expected_lines.len() <= 1 {
+        return Err( format!("[{}] No expected balances", name) );
+    }
+
+    match expected_lines.iter().zip(&actual_lines).position(|(e, a)| e != a) {
+        Some(i) => Err( format!("[{}] Line: {} expected: {} actual: {}", name, i + 1, expected_lines[i], actual_lines[i]) ),
+        None if expected_lines.len() != actual_lines.len() => {
+            Err( format!("[{}] Lines: {} expected: {}", name, actual_lines.len(), expected_lines.len()) )
+        },
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn expected_balances_match() {
+    let option_list: [&[&str]; 4] = [
+        &["--rows", "2000"],
+        &["--rows", "2000", "--clients", "5"],
+        &["--rows", "2000", "--clients", "3", "--invalid", "0.2"],
+        &["--rows", "1000", "--clients", "1", "--mix", "deposit=5,dispute=3,resolve=1,chargeback=2,unlock=1"],
+    ];
+
+    let failure_list: Vec<String> = SEED_LIST.iter()
+                                             .flat_map(|s| option_list.iter().map(move |o| (*s, *o)))
+                                             .filter_map(|(s, o)| check_generated(s, o).err())
+                                             .collect();
+
+    if !failure_list.is_empty() {
+        panic!("{} generated files differ\n{}", failure_list.len(), failure_list.join("\n"));
+    }
+}
+
+#[test]
+fn no_invalid_rows_unless_requested() {
+    for seed in SEED_LIST.iter() {
+        let output = run_tool(&["generate", "--seed", &seed.to_string(), "--rows", "3000", "--clients", "2", "--output", temp_file("valid.csv").to_str().unwrap()]);
+        let _ = fs::remove_file(temp_file("valid.csv"));
+
+        let message = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "Seed: {} {}", seed, message);
+        assert!(message.contains(", 0 of them invalid"), "Seed: {} {}", seed, message);
+    }
+}
+
+#[test]
+fn all_accounts_locked_is_an_error() {
+    let output_file = temp_file("locked.csv");
+    let output = run_tool(&["generate", "--seed", "5", "--rows", "3000", "--clients", "1",
+                            "--mix", "deposit=5,dispute=3,chargeback=2", "--output", output_file.to_str().unwrap()]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no unlocks"));
+    assert!(!output_file.exists(), "Output file written: {}", output_file.display());
+}
@@ -0,0 +1,190 @@
+/*
+ *  Golden file tests. Every 'test/<case>.csv' with a 'test/<case>.expected.csv' is processed
+ *  by the tool and its balances are compared with the expected ones. Inputs can also be
+ *  '.json' or '.ndjson', and the expected balances '.expected.json' or '.expected.ndjson',
+ *  i.e. with --output-format in the args.
+ *
+ *  Optional files of a case:
+ *     <case>.rejects.csv  - Expected rejects file (--rejects)
+ *     <case>.exit         - Expected exit code. 0 by default
+ *     <case>.args         - Options passed to the tool, separated by blanks
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+
+/// Extensions of the inputs and the expected balances
+const FORMAT_LIST: [&str; 3] = ["csv", "json", "ndjson"];
+
+
+/**
+ * Files of a test case
+ */
+struct GoldenCase {
+    name:          String,
+    input:         PathBuf,
+    expected:      PathBuf,
+    rejects:       Option<PathBuf>,
+    exit_code:     i32,
+    args:          Vec<String>,
+}
+
+#[test]
+fn golden_files() {
+    let test_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+    let case_list = find_cases(&test_dir);
+    assert!(!case_list.is_empty(), "No golden test cases found in {}", test_dir.display());
+
+    let failure_list: Vec<String> = case_list.iter()
+                                             .filter_map(|c| run_case(&test_dir, c).err())
+                                             .collect();
+
+    if !failure_list.is_empty() {
+        panic!("{} of {} golden test cases failed\n\n{}", failure_list.len(), case_list.len(), failure_list.join("\n"));
+    }
+}
+
+/**
+ * Inputs of the test directory with an expected output, ordered by name
+ */
+fn find_cases(in_dir: &Path) -> Vec<GoldenCase> {
+    let mut case_list = Vec::new();
+
+    for entry in fs::read_dir(in_dir).expect("Reading test directory") {
+        let input = entry.expect("Reading test directory").path();
+
+        let name = match (input.file_stem().and_then(|s| s.to_str()), input.extension()) {
+            // Expected files are 'name.expected.csv', 'name.rejects.csv', ...
+            (Some(stem), Some(ext)) if FORMAT_LIST.iter().any(|f| ext == *f) && !stem.contains('.') => stem.to_string(),
+            _                                                                                      => continue,
+        };
+
+        let expected = match FORMAT_LIST.iter()
+                                        .map(|f| in_dir.join(format!("{}.expected.{}", name, f)))
+                                        .find(|p| p.exists()) {
+            Some(path) => path,
+            None       => continue,
+        };
+
+        let rejects = Some(in_dir.join(format!("{}.rejects.csv", name))).filter(|p| p.exists());
+
+        let exit_code = match fs::read_to_string(in_dir.join(format!("{}.exit", name))) {
+            Ok(text) => text.trim().parse().unwrap_or_else(|_| panic!("Invalid exit code in {}.exit", name)),
+            Err(_)   => 0,
+        };
+
+        let args = fs::read_to_string(in_dir.join(format!("{}.args", name)))
+                      .map(|text| text.split_whitespace().map(String::from).collect())
+                      .unwrap_or_default();
+
+        case_list.push(GoldenCase { name, input, expected, rejects, exit_code, args });
+    }
+
+    case_list.sort_by(|a, b| a.name.cmp(&b.name));
+    case_list
+}
+
+/**
+ * Run the tool on a case. It returns the differences, if any
+ */
+fn run_case(in_dir: &Path, in_case: &GoldenCase) -> Result<(), String> {
+    let rejects_file = env::temp_dir().join(format!("golden_{}_{}.rejects.csv", process::id(), in_case.name));
+
+    // Run from the test directory, so the rejects file shows the input name only
+    let mut command = Command::new(env!("CARGO_BIN_EXE_csv_payment"));
+    command.current_dir(in_dir)
+           .args(&in_case.args);
+    if in_case.rejects.is_some() {
+        command.arg("--rejects").arg(&rejects_file);
+    }
+    command.arg(in_case.input.file_name().expect("Input file name"));
+
+    let output = command.output().map_err(|e| format!("[{}] Unable to run the tool: {}", in_case.name, e))?;
+    let mut error_list = Vec::new();
+
+    let exit_code = output.status.code().unwrap_or(-1);
+    // The tool exits with -1, which is seen as 255
+    if exit_code != in_case.exit_code && exit_code != in_case.exit_code & 0xFF {
+        error_list.push(format!("Exit code: {}, expected: {}\n{}", exit_code, in_case.exit_code, String::from_utf8_lossy(&output.stderr)));
+    }
+
+    let expected = fs::read_to_string(&in_case.expected).map_err(|e| format!("[{}] Reading expected output: {}", in_case.name, e))?;
+    let actual = String::from_utf8_lossy(&output.stdout);
+    if let Some(d) = diff(&expected, &actual) {
+        error_list.push(format!("Balances differ (- expected, + actual):\n{}", d));
+    }
+
+    if let Some(expected_rejects) = &in_case.rejects {
+        let expected = fs::read_to_string(expected_rejects).map_err(|e| format!("[{}] Reading expected rejects: {}", in_case.name, e))?;
+        let actual = fs::read_to_string(&rejects_file).unwrap_or_default();
+        let _ = fs::remove_file(&rejects_file);
+
+        if let Some(d) = diff(&expected, &actual) {
+            error_list.push(format!("Rejects differ (- expected, + actual):\n{}", d));
+        }
+    }
+
+    if error_list.is_empty() {
+        Ok(())
+    } else {
+        Err( format!("[{}]\n{}", in_case.name, error_list.join("\n")) )
+    }
+}
+
+/**
+ * Lines without line end differences, trailing blanks or empty lines at the end
+ */
+fn normalize(in_text: &str) -> Vec<&str> {
+    let mut line_list: Vec<&str> = in_text.lines().map(|l| l.trim_end()).collect();
+    while line_list.last() == Some(&"") {
+        line_list.pop();
+    }
+    line_list
+}
+
+/**
+ * Line by line differences of two texts, after normalizing them. None if they are equal
+ */
+fn diff(in_expected: &str, in_actual: &str) -> Option<String> {
+    let expected = normalize(in_expected);
+    let actual = normalize(in_actual);
+    if expected == actual {
+        return None;
+    }
+
+    // Longest common subsequence, from the end
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut text = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            text += &format!("    {}\n", expected[i]);
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || common[i + 1][j] >= common[i][j + 1]) {
+            text += &format!("  - {}\n", expected[i]);
+            i += 1;
+        } else {
+            text += &format!("  + {}\n", actual[j]);
+            j += 1;
+        }
+    }
+    Some(text)
+}
@@ -0,0 +1,68 @@
+/*
+ *  Tests of several inputs in one run: files, a pattern and the standard input are one
+ *  continuous ledger, and each rejected row is reported with its own input and line
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{self, Command, Stdio};
+
+
+#[test]
+fn files_pattern_and_stdin() {
+    let dir = env::temp_dir().join(format!("inputs_{}", process::id()));
+    fs::create_dir_all(&dir).expect("Creating the test directory");
+
+    fs::write(dir.join("first.csv"), "type,client,tx,amount\n\
+                                      deposit,1,1,10\n\
+                                      deposit,2,2,5\n\
+                                      withdrawal,2,3,8\n").unwrap();
+    fs::write(dir.join("part_1.csv"), "type,client,tx,amount\n\
+                                       deposit,1,4,2.5\n\
+                                       deposit,3,1,7\n").unwrap();
+    fs::write(dir.join("part_2.csv"), "type,client,tx,amount\n\
+                                       dispute,1,1,\n\
+                                       withdrawal,1,5,1\n\
+                                       resolve,2,2,\n").unwrap();
+    // Not matched by the pattern
+    fs::write(dir.join("other.csv"), "type,client,tx,amount\n\
+                                      deposit,9,9,1\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_csv_payment"))
+                            .current_dir(&dir)
+                            .args(["first.csv", "part_*.csv", "-", "--rejects", "rejects.csv"])
+                            .stdin(Stdio::piped())
+                            .stdout(Stdio::piped())
+                            .stderr(Stdio::piped())
+                            .spawn()
+                            .expect("Running the tool");
+    child.stdin.take()
+         .unwrap()
+         .write_all(b"type,client,tx,amount\nresolve,1,1,\nwithdrawal,3,6,9\ndeposit,2,7,1\n")
+         .unwrap();
+    let output = child.wait_with_output().expect("Running the tool");
+    let rejects = fs::read_to_string(dir.join("rejects.csv")).unwrap_or_default();
+    let _ = fs::remove_dir_all(&dir);
+
+    assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               "client,available,held,total,locked\n\
+                1,11.5000,0.0000,11.5000,false\n\
+                2,6.0000,0.0000,6.0000,false\n");
+
+    let reject_list: Vec<String> = rejects.lines()
+                                          .map(|l| l.split(',').take(7).collect::<Vec<&str>>().join(","))
+                                          .collect();
+    assert_eq!(reject_list, vec![
+        "type,client,tx,amount,file,line,reason",
+        "withdrawal,2,3,8,first.csv,4,insufficient_funds",
+        "deposit,3,1,7,part_1.csv,3,duplicate_tx",
+        "resolve,2,2,,part_2.csv,4,not_disputed",
+        "withdrawal,3,6,9,<stdin>,3,insufficient_funds",
+    ]);
+}
@@ -0,0 +1,222 @@
+/*
+ *  Property tests of the payment engine. Random sequences of transactions are applied
+ *  and, after each one, the accounts are checked against the ledger invariants and
+ *  against a simple reference model. Failing sequences are shrunk to a minimal one
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::collections::{HashMap, HashSet};
+
+use proptest::prelude::*;
+
+use csv_payment::{Amount, ClientAccount, PaymentEngine, Transaction, TransactionKind};
+
+
+/// Few clients and transaction ids, so transactions often refer to each other
+const MAX_CLIENT: u16 = 4;
+const MAX_TX_ID:  u32 = 40;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DepositState {
+    Normal,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ModelAccount {
+    available:     i64,
+    held:          i64,
+    total:         i64,
+    locked:        bool,
+}
+
+/**
+ * Reference model of the ledger, written as plainly as possible. Accounts are
+ * locked by a chargeback and operations on them are rejected until unlocked
+ */
+#[derive(Debug, Default)]
+struct Model {
+    accounts:      HashMap<u16, ModelAccount>,
+    deposits:      HashMap<u32, (u16, i64, DepositState)>,
+    used_ids:      HashSet<u32>,
+}
+
+impl Model {
+    fn account(&self, in_client_id: u16) -> ModelAccount {
+        self.accounts.get(&in_client_id).copied().unwrap_or_default()
+    }
+
+    /**
+     * Apply a transaction. It returns false if it is rejected, with no changes
+     */
+    fn apply(&mut self, in_tx: &Transaction) -> bool {
+        let mut account = self.account(in_tx.client_id);
+        let amount = in_tx.amount.map_or(0, |a| a.raw());
+
+        if account.locked && in_tx.kind != TransactionKind::Unlock {
+            return false;
+        }
+
+        match in_tx.kind {
+            TransactionKind::Deposit => {
+                if amount <= 0 || self.used_ids.contains(&in_tx.tx_id) {
+                    return false;
+                }
+                account.available += amount;
+                account.total     += amount;
+                self.used_ids.insert(in_tx.tx_id);
+                self.deposits.insert(in_tx.tx_id, (in_tx.client_id, amount, DepositState::Normal));
+            },
+            TransactionKind::Withdrawal => {
+                if amount <= 0 || account.available < amount || self.used_ids.contains(&in_tx.tx_id) {
+                    return false;
+                }
+                account.available -= amount;
+                account.total     -= amount;
+                self.used_ids.insert(in_tx.tx_id);
+            },
+            TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Chargeback => {
+                let (client_id, deposit_amount, state) = match self.deposits.get(&in_tx.tx_id) {
+                    Some(d) => *d,
+                    None    => return false,
+                };
+                if client_id != in_tx.client_id {
+                    return false;
+                }
+
+                let new_state = match (in_tx.kind, state) {
+                    (TransactionKind::Dispute,    DepositState::Normal)   => DepositState::Disputed,
+                    (TransactionKind::Resolve,    DepositState::Disputed) => DepositState::Resolved,
+                    (TransactionKind::Chargeback, DepositState::Disputed) => DepositState::ChargedBack,
+                    _                                                     => return false,
+                };
+
+                match new_state {
+                    DepositState::Disputed => {
+                        account.available -= deposit_amount;
+                        account.held      += deposit_amount;
+                    },
+                    DepositState::Resolved => {
+                        account.available += deposit_amount;
+                        account.held      -= deposit_amount;
+                    },
+                    _ => {
+                        account.held      -= deposit_amount;
+                        account.total     -= deposit_amount;
+                        account.locked     = true;
+                    },
+                }
+                self.deposits.insert(in_tx.tx_id, (client_id, deposit_amount, new_state));
+            },
+            TransactionKind::Unlock => {
+                if !account.locked {
+                    return false;
+                }
+                account.locked = false;
+            },
+        }
+
+        self.accounts.insert(in_tx.client_id, account);
+        true
+    }
+}
+
+/**
+ * Balance of a client in the engine. Clients without account have zero balance
+ */
+fn engine_account(in_engine: &PaymentEngine, in_client_id: u16) -> ModelAccount {
+    match in_engine.account(in_client_id) {
+        Some(a) => ModelAccount {
+            available:  a.available.raw(),
+            held:       a.held.raw(),
+            total:      a.total.raw(),
+            locked:     a.locked,
+        },
+        None => ModelAccount::default(),
+    }
+}
+
+fn balances(in_accounts: &[ClientAccount]) -> HashMap<u16, (Amount, Amount, Amount, bool)> {
+    in_accounts.iter()
+               .map(|a| (a.client_id, (a.available, a.held, a.total, a.locked)))
+               .collect()
+}
+
+fn transaction_strategy() -> impl Strategy<Value = Transaction> {
+    let kind = prop_oneof![
+        4 => Just(TransactionKind::Deposit),
+        3 => Just(TransactionKind::Withdrawal),
+        3 => Just(TransactionKind::Dispute),
+        2 => Just(TransactionKind::Resolve),
+        1 => Just(TransactionKind::Chargeback),
+        1 => Just(TransactionKind::Unlock),
+    ];
+    // Mostly valid amounts, up to 1000.0000, and some zero or negative ones
+    let amount = prop_oneof![
+        9 => (1i64..=10_000_000).prop_map(Some),
+        1 => (-10_000i64..=0).prop_map(Some),
+    ];
+
+    (kind, 1..=MAX_CLIENT, 1..=MAX_TX_ID, amount).prop_map(|(kind, client_id, tx_id, amount)| Transaction {
+        kind,
+        client_id,
+        tx_id,
+        amount: match kind {
+            TransactionKind::Deposit | TransactionKind::Withdrawal => amount.map(Amount::from_raw),
+            _                                                      => None,
+        },
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(500))]
+
+    #[test]
+    fn ledger_invariants(tx_list in prop::collection::vec(transaction_strategy(), 1..120)) {
+        let mut engine = PaymentEngine::new();
+        let mut model = Model::default();
+
+        for (step, tx) in tx_list.iter().enumerate() {
+            let before = balances(&engine.snapshot());
+
+            let accepted = engine.apply(tx).is_ok();
+            prop_assert_eq!(accepted, model.apply(tx), "Step {}: {:?} accepted by the engine: {}", step, tx, accepted);
+
+            let after = balances(&engine.snapshot());
+            if !accepted {
+                prop_assert_eq!(&before, &after, "Step {}: rejected {:?} changed the accounts", step, tx);
+            }
+
+            for account in engine.accounts() {
+                prop_assert_eq!(account.available.checked_add(account.held), Some(account.total),
+                                "Step {}: available + held != total for client {}", step, account.client_id);
+                prop_assert!(account.held >= Amount::ZERO, "Step {}: held is negative for client {}", step, account.client_id);
+            }
+
+            // Only the account of the transaction may change
+            for client_id in 1..=MAX_CLIENT {
+                let unchanged = before.get(&client_id).copied()
+                                      .unwrap_or((Amount::ZERO, Amount::ZERO, Amount::ZERO, false));
+                let current   = after.get(&client_id).copied()
+                                     .unwrap_or((Amount::ZERO, Amount::ZERO, Amount::ZERO, false));
+                if client_id != tx.client_id {
+                    prop_assert_eq!(unchanged, current, "Step {}: {:?} changed client {}", step, tx, client_id);
+                }
+
+                // The total only changes through deposits, withdrawals and chargebacks
+                if matches!(tx.kind, TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Unlock) {
+                    prop_assert_eq!(unchanged.2, current.2, "Step {}: {:?} changed the total of client {}", step, tx, client_id);
+                }
+
+                prop_assert_eq!(engine_account(&engine, client_id), model.account(client_id),
+                                "Step {}: {:?} client {} differs from the model", step, tx, client_id);
+            }
+        }
+    }
+}
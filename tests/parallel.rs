@@ -0,0 +1,121 @@
+/*
+ *  Tests of the parallel mode. Random ledgers are processed with several numbers of
+ *  threads, and the balances and rejected rows are compared with a run in one thread
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+
+const SEED_LIST: [u64; 4] = [1, 7, 2021, 987_654_321];
+
+const THREAD_LIST: [&str; 3] = ["1", "3", "7"];
+
+
+/**
+ * SplitMix64, so the ledgers are the same in every run
+ */
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, in_limit: u64) -> u64 {
+        self.next() % in_limit
+    }
+}
+
+fn temp_file(in_name: &str) -> PathBuf {
+    env::temp_dir().join(format!("parallel_{}_{}", process::id(), in_name))
+}
+
+/**
+ * Ledger of interleaved clients. Disputes, resolves and chargebacks often refer to the
+ * deposits of other clients, and some ids are used twice, so rows depend on other threads
+ */
+fn write_ledger(in_seed: u64, in_rows: u32) -> PathBuf {
+    let mut random = Random(in_seed);
+    let mut text = String::from("type,client,tx,amount\n");
+    let mut next_tx_id = 1u64;
+
+    for _ in 0..in_rows {
+        let client = 1 + random.below(20);
+        let old_tx_id = 1 + random.below(next_tx_id);
+        let amount = format!("{}.{:04}", random.below(500), random.below(10_000));
+
+        let row = match random.below(20) {
+            0..=7   => { next_tx_id += 1; format!("deposit,{},{},{}", client, next_tx_id - 1, amount) },
+            8..=11  => { next_tx_id += 1; format!("withdrawal,{},{},{}", client, next_tx_id - 1, amount) },
+            12..=13 => format!("dispute,{},{},", client, old_tx_id),
+            14      => format!("resolve,{},{},", client, old_tx_id),
+            15      => format!("chargeback,{},{},", client, old_tx_id),
+            16..=17 => format!("unlock,{},{},", client, next_tx_id + 1_000_000),
+            18      => format!("deposit,{},{},{}", client, old_tx_id, amount),
+            _       => format!("withdrawal,{},{},{}", client, next_tx_id, amount),
+        };
+        text.push_str(&row);
+        text.push('\n');
+    }
+
+    let path = temp_file(&format!("{}.csv", in_seed));
+    fs::write(&path, text).expect("Writing the ledger");
+    path
+}
+
+/**
+ * Balances and sorted rejected rows of a run. The threads report the rejected rows as
+ * they are applied, so their order may change
+ */
+fn run_tool(in_input: &PathBuf, in_threads: &str, in_options: &[&str]) -> (String, Vec<String>) {
+    let rejects = temp_file(&format!("{}_{}.rejects.csv", in_threads, in_options.join("_")));
+    let output = Command::new(env!("CARGO_BIN_EXE_csv_payment"))
+                         .arg(in_input)
+                         .args(["--threads", in_threads, "--rejects", rejects.to_str().unwrap()])
+                         .args(in_options)
+                         .output()
+                         .expect("Running the tool");
+    // Some rows are always rejected
+    assert_eq!(output.status.code(), Some(1), "Threads: {} {}", in_threads, String::from_utf8_lossy(&output.stderr));
+
+    let mut reject_list: Vec<String> = fs::read_to_string(&rejects).unwrap_or_default()
+                                                                   .lines()
+                                                                   .map(str::to_string)
+                                                                   .collect();
+    reject_list.sort();
+    let _ = fs::remove_file(&rejects);
+    (String::from_utf8_lossy(&output.stdout).into_owned(), reject_list)
+}
+
+#[test]
+fn same_output_with_any_number_of_threads() {
+    let option_list: [&[&str]; 2] = [&[], &["--locked", "queue"]];
+
+    for seed in SEED_LIST.iter() {
+        let input = write_ledger(*seed, 5000);
+
+        for options in option_list.iter() {
+            let (balances, reject_list) = run_tool(&input, THREAD_LIST[0], options);
+            assert!(balances.lines().count() > 1, "Seed: {} no balances", seed);
+            assert!(!reject_list.is_empty(), "Seed: {} no rejected rows", seed);
+
+            for threads in THREAD_LIST[1..].iter() {
+                let (parallel_balances, parallel_reject_list) = run_tool(&input, threads, options);
+                assert_eq!(parallel_balances, balances, "Seed: {} threads: {} options: {:?}", seed, threads, options);
+                assert_eq!(parallel_reject_list, reject_list, "Seed: {} threads: {} options: {:?}", seed, threads, options);
+            }
+        }
+        let _ = fs::remove_file(&input);
+    }
+}
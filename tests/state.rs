@@ -0,0 +1,164 @@
+/*
+ *  Tests of the state between runs. A ledger is processed in two runs that keep their
+ *  state, with the memory and the disk store, and the results are compared with the ones
+ *  of a single run
+ *
+ *  Author:    Alberto Fernandez
+ *  Date:      13/02/2021
+ *  Version:   0.9
+ */
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+
+use serde_json::Value;
+
+
+const LEDGER_ROWS: u32 = 400;
+
+
+/**
+ * Row of the test ledger. Disputes, resolves and chargebacks refer to deposits made long
+ * before, so many of them cross from the first run to the second one. Chargebacks lock
+ * accounts, and unlocks replay the operations queued meanwhile
+ */
+fn ledger_row(in_row: u32) -> String {
+    // Client of the deposit or withdrawal of a row
+    let client_of = |row: u32| row % 9 + 1;
+
+    match (in_row % 12, in_row.checked_sub(71)) {
+        (5..=6, _)        => format!("withdrawal,{},{},{}.5", client_of(in_row), in_row, in_row % 70),
+        (7, Some(_))      => format!("dispute,{},{},", client_of(in_row - 55), in_row - 55),
+        (8, Some(_))      => format!("resolve,{},{},", client_of(in_row - 56), in_row - 56),
+        (9, Some(_))      => format!("dispute,{},{},", client_of(in_row - 56), in_row - 56),
+        (10, Some(_))     => format!("chargeback,{},{},", client_of(in_row - 57), in_row - 57),
+        // Client locked by the chargeback of 13 rows before
+        (11, Some(_))     => format!("unlock,{},{},", client_of(in_row - 70), in_row),
+        _                 => format!("deposit,{},{},{}.75", client_of(in_row), in_row, in_row % 50),
+    }
+}
+
+fn write_ledger(in_name: &str, in_rows: std::ops::RangeInclusive<u32>) -> PathBuf {
+    let mut text = String::from("type,client,tx,amount\n");
+    for row in in_rows {
+        text.push_str(&ledger_row(row));
+        text.push('\n');
+    }
+
+    let path = temp_file(in_name);
+    fs::write(&path, text).expect("Writing the ledger");
+    path
+}
+
+fn temp_file(in_name: &str) -> PathBuf {
+    env::temp_dir().join(format!("state_{}_{}", process::id(), in_name))
+}
+
+/**
+ * Run the tool on an input. It returns the balances and the rejected rows without their
+ * input name and line
+ */
+fn run_tool(in_name: &str, in_input: &Path, in_state: &Path, in_options: &[&str]) -> (Output, Vec<String>) {
+    let rejects = temp_file(&format!("{}.rejects.csv", in_name));
+    let output = Command::new(env!("CARGO_BIN_EXE_csv_payment"))
+                         .arg(in_input)
+                         .args(["--state", in_state.to_str().unwrap(), "--rejects", rejects.to_str().unwrap()])
+                         .args(in_options)
+                         .output()
+                         .expect("Running the tool");
+
+    let reject_list = fs::read_to_string(&rejects).unwrap_or_default()
+                                                  .lines()
+                                                  .skip(1)
+                                                  .map(|l| {
+                                                      let field_list: Vec<&str> = l.split(',').collect();
+                                                      format!("{},{}", field_list[..4].join(","), field_list[6])
+                                                  })
+                                                  .collect();
+    let _ = fs::remove_file(&rejects);
+    (output, reject_list)
+}
+
+/**
+ * State file without the input and line of the queued operations, which are not the same
+ * when the ledger is split
+ */
+fn state_without_sources(in_text: &str) -> Value {
+    let mut state: Value = serde_json::from_str(in_text).expect("Reading the state file");
+    for account in state["accounts"].as_array_mut().into_iter().flatten() {
+        for pending in account["pending"].as_array_mut().into_iter().flatten() {
+            if let Value::Object(fields) = pending {
+                fields.remove("source");
+                fields.remove("line");
+            }
+        }
+    }
+    state
+}
+
+/**
+ * Process the ledger in one run, and split in two runs. Balances, rejected rows and, with
+ * the memory store, the state files shall be the same
+ */
+fn check_split(in_name: &str, in_options: &[&str], in_store: bool) {
+    let full_input = write_ledger(&format!("{}_full.csv", in_name), 1..=LEDGER_ROWS);
+    let first_input = write_ledger(&format!("{}_first.csv", in_name), 1..=LEDGER_ROWS / 2);
+    let second_input = write_ledger(&format!("{}_second.csv", in_name), LEDGER_ROWS / 2 + 1..=LEDGER_ROWS);
+    let full_state = temp_file(&format!("{}_full.state", in_name));
+    let split_state = temp_file(&format!("{}_split.state", in_name));
+    let full_store = temp_file(&format!("{}_full.db", in_name));
+    let split_store = temp_file(&format!("{}_split.db", in_name));
+
+    let full_options: Vec<&str> = in_options.iter().copied().chain(["--store", full_store.to_str().unwrap()]).collect();
+    let split_options: Vec<&str> = in_options.iter().copied().chain(["--store", split_store.to_str().unwrap()]).collect();
+    let (full_options, split_options) = if in_store {
+        (&full_options[..], &split_options[..])
+    } else {
+        (in_options, in_options)
+    };
+
+    let (full, full_reject_list) = run_tool(in_name, &full_input, &full_state, full_options);
+    let (first, mut split_reject_list) = run_tool(in_name, &first_input, &split_state, split_options);
+    let (second, second_reject_list) = run_tool(in_name, &second_input, &split_state, split_options);
+    split_reject_list.extend(second_reject_list);
+
+    let full_state_text = fs::read_to_string(&full_state).unwrap_or_default();
+    let split_state_text = fs::read_to_string(&split_state).unwrap_or_default();
+    for path in [&full_input, &first_input, &second_input, &full_state, &split_state, &full_store, &split_store].iter() {
+        let _ = fs::remove_file(path);
+    }
+
+    for output in [&full, &first, &second].iter() {
+        assert_eq!(output.status.code(), Some(1), "[{}] {}", in_name, String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(full.stdout.len() > 40, "[{}] No balances", in_name);
+    assert!(!full_reject_list.is_empty(), "[{}] No rejected rows", in_name);
+
+    assert_eq!(String::from_utf8_lossy(&second.stdout), String::from_utf8_lossy(&full.stdout), "[{}] Balances", in_name);
+    assert_eq!(split_reject_list, full_reject_list, "[{}] Rejected rows", in_name);
+    if !in_store {
+        assert_eq!(state_without_sources(&split_state_text), state_without_sources(&full_state_text), "[{}] State files", in_name);
+    }
+}
+
+#[test]
+fn split_run_memory_store() {
+    check_split("memory", &[], false);
+}
+
+#[test]
+fn split_run_memory_store_queue() {
+    check_split("memory_queue", &["--locked", "queue"], false);
+}
+
+#[test]
+fn split_run_disk_store() {
+    check_split("disk", &[], true);
+}
+
+#[test]
+fn split_run_disk_store_queue() {
+    check_split("disk_queue", &["--locked", "queue"], true);
+}